@@ -1,117 +1,615 @@
 use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
 use quote::{format_ident, quote};
 use syn::{
-    parse_macro_input, punctuated::Punctuated, AttributeArgs, FnArg, GenericArgument, ItemTrait,
-    Lit, LitStr, Meta, NestedMeta, Pat, PathArguments, ReturnType, TraitItem, TraitItemMethod,
+    parse_macro_input, parse_quote,
+    visit_mut::{self, VisitMut},
+    Attribute, AttributeArgs, FnArg, GenericArgument, GenericParam, Generics, ItemTrait, Lit,
+    LitInt, LitStr, Meta, NestedMeta, Pat, PathArguments, ReturnType, TraitItem, TraitItemMethod,
     Type,
 };
 
-fn return_inner_type(
-    ty: &ReturnType,
-) -> Result<&Punctuated<GenericArgument, syn::token::Comma>, &'static str> {
-    if let ReturnType::Type(_, ty) = &ty {
+/// the T in the Result<T> returned by the method. it can be overridden
+/// with #[returns(T)] if the return type is an alias of Result
+fn return_inner_type(m: &TraitItemMethod) -> syn::Result<Type> {
+    if let Some(attr) = m.attrs.iter().find(|a| a.path.is_ident("returns")) {
+        return attr.parse_args::<Type>();
+    }
+
+    if let ReturnType::Type(_, ty) = &m.sig.output {
         if let Type::Path(p) = ty.as_ref() {
             if let Some(seg) = p.path.segments.iter().last() {
-                if format!("{}", seg.ident) == "Result" {
+                if seg.ident == "Result" {
                     if let PathArguments::AngleBracketed(inner) = &seg.arguments {
-                        return Ok(&inner.args);
+                        if let Some(GenericArgument::Type(ty)) = inner.args.first() {
+                            return Ok(ty.clone());
+                        }
                     }
                 }
             }
         }
+
+        return Err(syn::Error::new_spanned(
+            ty,
+            "all interface methods must return Result<T>, use #[returns(T)] if the return type is an alias of Result<T>",
+        ));
     }
 
-    Err("all interface method must return Result<T>")
+    Err(syn::Error::new_spanned(
+        &m.sig,
+        "all interface methods must return Result<T>",
+    ))
 }
-fn sender_inner_type(
-    ty: &FnArg,
-) -> Result<&Punctuated<GenericArgument, syn::token::Comma>, &'static str> {
-    if let FnArg::Typed(t) = &ty {
-        if let Type::Path(p) = t.ty.as_ref() {
-            if let Some(seg) = p.path.segments.iter().last() {
-                if format!("{}", seg.ident) == "Sender" {
-                    if let PathArguments::AngleBracketed(inner) = &seg.arguments {
-                        return Ok(&inner.args);
+
+/// the T in a `Name<T>` type
+fn wrapped_type(ty: &Type, name: &str) -> Option<Type> {
+    if let Type::Path(p) = ty {
+        if let Some(seg) = p.path.segments.iter().last() {
+            if seg.ident == name {
+                if let PathArguments::AngleBracketed(inner) = &seg.arguments {
+                    if let Some(GenericArgument::Type(ty)) = inner.args.first() {
+                        return Some(ty.clone());
                     }
                 }
             }
         }
     }
 
-    Err("all interface method must return Result<T>")
+    None
 }
-fn method_name(m: &TraitItemMethod) -> String {
-    for attr in m.attrs.iter() {
-        if !attr.path.is_ident("rename") {
-            continue;
+
+fn sender_inner_type(ty: &FnArg) -> syn::Result<Type> {
+    if let FnArg::Typed(t) = &ty {
+        if let Some(ty) = wrapped_type(&t.ty, "Sender") {
+            return Ok(ty);
         }
+    }
 
-        let meta = attr
-            .parse_meta()
-            .expect("rename require single string literal");
+    Err(syn::Error::new_spanned(
+        ty,
+        "stream argument must be of type server::Sender<T>",
+    ))
+}
 
-        if let Meta::List(l) = meta {
-            if !l.path.is_ident("rename") {
-                continue;
+/// the T of a call that streams its results by returning `Result<Items<T>>`
+fn streams_items(m: &TraitItemMethod) -> syn::Result<Option<Type>> {
+    Ok(wrapped_type(&return_inner_type(m)?, "Items"))
+}
+
+/// argument of a call that is sent over a pipe instead of the request
+enum PipeArg {
+    /// `Stream<T>` items sent by the client
+    Input(Type),
+    /// `Sender<T>` items sent by the handler
+    Output(Type),
+}
+
+fn pipe_arg(arg: &FnArg) -> Option<PipeArg> {
+    let ty = match arg {
+        FnArg::Typed(t) => t.ty.as_ref(),
+        FnArg::Receiver(_) => return None,
+    };
+
+    wrapped_type(ty, "Stream")
+        .map(PipeArg::Input)
+        .or_else(|| wrapped_type(ty, "Sender").map(PipeArg::Output))
+}
+
+/// the item types of the input and output pipes of a call
+struct Pipes {
+    input: Option<Type>,
+    output: Option<Type>,
+}
+
+fn call_pipes(m: &TraitItemMethod) -> syn::Result<Pipes> {
+    let mut pipes = Pipes {
+        input: None,
+        output: None,
+    };
+
+    for arg in m.sig.inputs.iter() {
+        let (slot, ty) = match pipe_arg(arg) {
+            Some(PipeArg::Input(ty)) => (&mut pipes.input, ty),
+            Some(PipeArg::Output(ty)) => (&mut pipes.output, ty),
+            None => continue,
+        };
+
+        if slot.replace(ty).is_some() {
+            return Err(syn::Error::new_spanned(
+                arg,
+                "a call can take at most one Stream and one Sender argument",
+            ));
+        }
+    }
+
+    if pipes.input.is_none() && pipes.output.is_none() {
+        return Ok(pipes);
+    }
+
+    if m.sig.asyncness.is_none() {
+        return Err(syn::Error::new_spanned(
+            &m.sig,
+            "calls with Stream or Sender arguments must be async",
+        ));
+    }
+
+    if pipes.input.is_none() {
+        return Err(syn::Error::new_spanned(
+            &m.sig.inputs,
+            "a call with a Sender argument must also take a Stream argument, return Result<Items<T>> to stream results to the caller",
+        ));
+    }
+
+    Ok(pipes)
+}
+
+/// replaces `Self::Name` associated types with a `Name` type parameter. Used
+/// to build the stub that is not an implementation of the trait.
+struct AssociatedTypes;
+
+impl VisitMut for AssociatedTypes {
+    fn visit_type_mut(&mut self, ty: &mut Type) {
+        if let Type::Path(p) = ty {
+            let segments = &p.path.segments;
+            if p.qself.is_none() && segments.len() == 2 && segments[0].ident == "Self" {
+                let ident = segments[1].ident.clone();
+                *ty = parse_quote!(#ident);
+                return;
+            }
+        }
+
+        visit_mut::visit_type_mut(self, ty);
+    }
+}
+
+/// replaces `Self::Name` associated types with `<T as Trait>::Name`. Used
+/// in the generated object that wraps the trait implementation T.
+struct QualifiedSelf(TokenStream2);
+
+impl VisitMut for QualifiedSelf {
+    fn visit_type_mut(&mut self, ty: &mut Type) {
+        if let Type::Path(p) = ty {
+            let segments = &p.path.segments;
+            if p.qself.is_none() && segments.len() == 2 && segments[0].ident == "Self" {
+                let ident = &segments[1].ident;
+                let qualified = &self.0;
+                *ty = parse_quote!(<#qualified>::#ident);
+                return;
             }
+        }
 
-            let meta = l
-                .nested
-                .first()
-                .expect("rename requires single string literal");
-            if let NestedMeta::Lit(Lit::Str(st)) = meta {
-                return st.value();
+        visit_mut::visit_type_mut(self, ty);
+    }
+}
+
+/// only type parameters are supported on object traits
+fn check_generics(generics: &Generics) -> syn::Result<()> {
+    for param in generics.params.iter() {
+        match param {
+            GenericParam::Type(_) => {}
+            GenericParam::Lifetime(l) => {
+                return Err(syn::Error::new_spanned(
+                    l,
+                    "lifetime parameters are not supported on object traits",
+                ))
+            }
+            GenericParam::Const(c) => {
+                return Err(syn::Error::new_spanned(
+                    c,
+                    "const parameters are not supported on object traits",
+                ))
             }
         }
     }
 
-    return format!("{}", m.sig.ident);
+    Ok(())
+}
+
+/// phantom type that carries all type parameters of generics
+fn phantom(generics: &Generics) -> TokenStream2 {
+    let params = generics.type_params().map(|p| &p.ident);
+    quote! { std::marker::PhantomData<fn() -> (#(#params,)*)> }
+}
+
+/// kind of a reference argument that is decoded without copying
+enum Borrowed {
+    Bytes,
+    Str,
+    Other,
+}
+
+fn borrowed(ty: &Type) -> Option<Borrowed> {
+    let elem = match ty {
+        Type::Reference(r) => r.elem.as_ref(),
+        _ => return None,
+    };
+
+    let kind = match elem {
+        Type::Slice(s) if matches!(s.elem.as_ref(), Type::Path(p) if p.path.is_ident("u8")) => {
+            Borrowed::Bytes
+        }
+        Type::Path(p) if p.path.is_ident("str") => Borrowed::Str,
+        _ => Borrowed::Other,
+    };
+
+    Some(kind)
+}
+
+/// expression that decodes the argument at index from the request inputs
+fn dispatch_arg(index: usize, arg: &FnArg) -> TokenStream2 {
+    let index = syn::Index::from(index);
+    let ty = match arg {
+        FnArg::Typed(t) => t.ty.as_ref(),
+        FnArg::Receiver(_) => unreachable!(),
+    };
+
+    match borrowed(ty) {
+        Some(Borrowed::Bytes) => {
+            quote! { &*request.inputs.at::<rbus::protocol::BytesRef>(#index)? }
+        }
+        Some(Borrowed::Str) => quote! { &*request.inputs.at::<rbus::protocol::StrRef>(#index)? },
+        Some(Borrowed::Other) => quote! { &request.inputs.at(#index)? },
+        None => quote! { request.inputs.at(#index)? },
+    }
+}
+
+/// expression that encodes the argument with name in the stub
+fn stub_arg(name: &syn::Ident, arg: &FnArg) -> TokenStream2 {
+    match arg {
+        FnArg::Typed(t) if matches!(borrowed(&t.ty), Some(Borrowed::Bytes)) => {
+            quote! { rbus::protocol::BytesRef::from(#name) }
+        }
+        _ => quote! { #name },
+    }
 }
 
-fn is_stream(m: &TraitItemMethod) -> bool {
-    // must take 2 arguments
-    if !m.attrs.iter().any(|att| att.path.is_ident("stream")) {
-        return false;
+fn method_name(m: &TraitItemMethod) -> syn::Result<String> {
+    match m.attrs.iter().find(|attr| attr.path.is_ident("rename")) {
+        Some(attr) => attr
+            .parse_args::<LitStr>()
+            .map(|name| name.value())
+            .map_err(|_| {
+                syn::Error::new_spanned(
+                    attr,
+                    "rename requires a single string literal, e.g. #[rename(\"Name\")]",
+                )
+            }),
+        None => Ok(m.sig.ident.to_string()),
     }
+}
+
+/// a concurrency limit, must be a positive integer
+fn concurrency_limit(lit: &Lit) -> syn::Result<LitInt> {
+    match lit {
+        Lit::Int(limit) if limit.base10_parse::<usize>()? > 0 => Ok(limit.clone()),
+        lit => Err(syn::Error::new_spanned(
+            lit,
+            "concurrency must be a positive integer",
+        )),
+    }
+}
+
+/// the priority of the calls to a method set with #[priority("high")], calls
+/// to other methods use the priority of the stub
+fn method_priority(m: &TraitItemMethod) -> syn::Result<TokenStream2> {
+    let attr = match m.attrs.iter().find(|attr| attr.path.is_ident("priority")) {
+        Some(attr) => attr,
+        None => return Ok(quote! { self.priority }),
+    };
+
+    let lit = attr.parse_args::<LitStr>().map_err(|_| {
+        syn::Error::new_spanned(
+            attr,
+            "priority requires a string literal, e.g. #[priority(\"high\")]",
+        )
+    })?;
+
+    match lit.value().as_str() {
+        "high" => Ok(quote! { rbus::protocol::Priority::High }),
+        "normal" => Ok(quote! { rbus::protocol::Priority::Normal }),
+        "low" => Ok(quote! { rbus::protocol::Priority::Low }),
+        _ => Err(syn::Error::new_spanned(
+            lit,
+            "priority must be one of \"high\", \"normal\" or \"low\"",
+        )),
+    }
+}
+
+/// the request builder of an idempotent method marked with #[idempotent], its
+/// calls get an idempotency key so the client can retry them
+fn method_idempotent(m: &TraitItemMethod) -> syn::Result<Option<TokenStream2>> {
+    match m.attrs.iter().find(|attr| attr.path.is_ident("idempotent")) {
+        Some(attr) if !attr.tokens.is_empty() => Err(syn::Error::new_spanned(
+            attr,
+            "idempotent takes no arguments, e.g. #[idempotent]",
+        )),
+        Some(_) => Ok(Some(quote! { .idempotent() })),
+        None => Ok(None),
+    }
+}
+
+/// the concurrency limit of a method set with #[concurrency(N)]
+fn method_concurrency(m: &TraitItemMethod) -> syn::Result<Option<LitInt>> {
+    let attr = match m
+        .attrs
+        .iter()
+        .find(|attr| attr.path.is_ident("concurrency"))
+    {
+        Some(attr) => attr,
+        None => return Ok(None),
+    };
+
+    let lit = attr.parse_args::<Lit>().map_err(|_| {
+        syn::Error::new_spanned(
+            attr,
+            "concurrency requires a positive integer, e.g. #[concurrency(1)]",
+        )
+    })?;
+
+    concurrency_limit(&lit).map(Some)
+}
+
+fn is_stream(m: &TraitItemMethod) -> syn::Result<bool> {
+    let attr = match m.attrs.iter().find(|attr| attr.path.is_ident("stream")) {
+        Some(attr) => attr,
+        None => return Ok(false),
+    };
+
+    stream_options(attr)?;
 
     if m.sig.asyncness.is_none() {
-        panic!("stream method must be async");
+        return Err(syn::Error::new_spanned(
+            &m.sig,
+            "stream method must be async",
+        ));
     }
 
-    if m.sig.inputs.len() != 2 {
-        panic!("stream method must take (&self, Sender<T>) as arguments");
+    // must take at least the receiver and the sender
+    if m.sig.inputs.len() < 2 {
+        return Err(syn::Error::new_spanned(
+            &m.sig.inputs,
+            "stream method must take (&self, [parameters..,] Sender<T>) as arguments",
+        ));
     }
+
+    // parameters are moved to the stream producer
+    for param in stream_params(m) {
+        if let FnArg::Typed(t) = param {
+            if let Type::Reference(_) = t.ty.as_ref() {
+                return Err(syn::Error::new_spanned(
+                    param,
+                    "stream parameters can not be borrowed",
+                ));
+            }
+        }
+    }
+
     // must return ()
     if m.sig.output != ReturnType::Default {
-        panic!("stream method must not return any type");
+        return Err(syn::Error::new_spanned(
+            &m.sig.output,
+            "stream method must not return any type",
+        ));
     }
 
-    let rec = &m.sig.inputs[1];
+    sender_inner_type(stream_sender(m))?;
 
-    if let FnArg::Typed(typ) = rec {
-        if let Type::Path(p) = typ.ty.as_ref() {
-            if let Some(seg) = p.path.segments.iter().last() {
-                if format!("{}", seg.ident) != "Sender" {
-                    panic!("argument must be of a server::Sender<T> type");
+    Ok(true)
+}
+
+/// options of a stream given as #[stream(name = "value", ..)]
+struct StreamOptions {
+    restart: TokenStream2,
+    buffer: TokenStream2,
+    overflow: TokenStream2,
+    // the stream only runs on the leader replica, set with #[stream(leader)]
+    leader: bool,
+}
+
+fn stream_options(attr: &Attribute) -> syn::Result<StreamOptions> {
+    let mut options = StreamOptions {
+        restart: quote! { rbus::server::Restart::Never },
+        buffer: quote! { rbus::server::DEFAULT_BUFFER },
+        overflow: quote! { rbus::server::Overflow::Block },
+        leader: false,
+    };
+
+    if attr.tokens.is_empty() {
+        return Ok(options);
+    }
+
+    let list = match attr.parse_meta()? {
+        Meta::List(list) => list,
+        meta => {
+            return Err(syn::Error::new_spanned(
+                meta,
+                "expected #[stream] or #[stream(option = value, ..)]",
+            ))
+        }
+    };
+
+    for nested in list.nested.iter() {
+        let value =
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(value)) => value,
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("leader") => {
+                    options.leader = true;
+                    continue;
                 }
+                _ => return Err(syn::Error::new_spanned(
+                    nested,
+                    "expected `restart = \"..\"`, `buffer = N`, `overflow = \"..\"` or `leader`",
+                )),
+            };
+
+        if value.path.is_ident("buffer") {
+            options.buffer = match &value.lit {
+                Lit::Int(size) if size.base10_parse::<usize>()? > 0 => quote! { #size },
+                lit => {
+                    return Err(syn::Error::new_spanned(
+                        lit,
+                        "buffer must be a positive integer",
+                    ))
+                }
+            };
+            continue;
+        }
+
+        let lit = match &value.lit {
+            Lit::Str(lit) => lit,
+            lit => {
+                return Err(syn::Error::new_spanned(
+                    lit,
+                    "value must be a string literal",
+                ))
             }
+        };
+
+        if value.path.is_ident("overflow") {
+            options.overflow = match lit.value().as_str() {
+                "block" => quote! { rbus::server::Overflow::Block },
+                "drop-oldest" => quote! { rbus::server::Overflow::DropOldest },
+                "drop-newest" => quote! { rbus::server::Overflow::DropNewest },
+                "coalesce-latest" => quote! { rbus::server::Overflow::CoalesceLatest },
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        lit,
+                        "overflow must be one of \"block\", \"drop-oldest\", \"drop-newest\" or \"coalesce-latest\"",
+                    ))
+                }
+            };
+            continue;
+        }
+
+        if value.path.is_ident("restart") {
+            options.restart = match lit.value().as_str() {
+                "never" => quote! { rbus::server::Restart::Never },
+                "on-panic" => quote! { rbus::server::Restart::OnPanic },
+                "always" => quote! { rbus::server::Restart::Always },
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        lit,
+                        "restart must be one of \"never\", \"on-panic\" or \"always\"",
+                    ))
+                }
+            };
+        } else {
+            return Err(syn::Error::new_spanned(
+                &value.path,
+                "unknown stream option, expected `restart`, `buffer`, `overflow` or `leader`",
+            ));
+        }
+    }
+
+    Ok(options)
+}
+
+/// options of a stream method, the method must be a valid stream
+fn method_stream_options(m: &TraitItemMethod) -> syn::Result<StreamOptions> {
+    match m.attrs.iter().find(|attr| attr.path.is_ident("stream")) {
+        Some(attr) => stream_options(attr),
+        None => Err(syn::Error::new_spanned(&m.sig, "not a stream method")),
+    }
+}
+
+/// the subscription parameters of a stream method (all arguments
+/// between the receiver and the sender)
+fn stream_params(m: &TraitItemMethod) -> impl Iterator<Item = &FnArg> {
+    let count = m.sig.inputs.len().saturating_sub(2);
+    m.sig.inputs.iter().skip(1).take(count)
+}
+
+/// the sender (last) argument of a stream method
+fn stream_sender(m: &TraitItemMethod) -> &FnArg {
+    &m.sig.inputs[m.sig.inputs.len() - 1]
+}
+
+/// the receiver of all object methods must be &self
+fn check_receiver(m: &TraitItemMethod) -> syn::Result<()> {
+    match m.sig.inputs.first() {
+        Some(FnArg::Receiver(r)) if r.reference.is_some() && r.mutability.is_none() => Ok(()),
+        Some(FnArg::Receiver(r)) => Err(syn::Error::new_spanned(
+            r,
+            "receiver must be a shared reference to self (&self)",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// name of a method argument, only simple identifiers are supported
+/// since the argument is forwarded as is by the stub
+fn arg_ident(arg: &FnArg) -> syn::Result<&syn::Ident> {
+    if let FnArg::Typed(a) = arg {
+        if let Pat::Ident(i) = a.pat.as_ref() {
+            return Ok(&i.ident);
         }
     }
 
-    return true;
+    Err(syn::Error::new_spanned(
+        arg,
+        "arguments must be simple identifiers, e.g. `name: Type`",
+    ))
+}
+
+/// helper attributes consumed by the object macro
+const RBUS_ATTRS: &[&str] = &[
+    "rename",
+    "stream",
+    "returns",
+    "concurrency",
+    "priority",
+    "idempotent",
+];
+
+fn is_rbus_attr(attr: &Attribute) -> bool {
+    RBUS_ATTRS.iter().any(|name| attr.path.is_ident(name))
+}
+
+/// attributes of the trait method that also apply to the generated code
+/// that calls it (dispatch), so the method can still be conditionally compiled
+fn cfg_attrs(m: &TraitItemMethod) -> Vec<&Attribute> {
+    m.attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("cfg"))
+        .collect()
 }
+
+/// attributes of the trait method that are copied to the stub method
+fn stub_attrs(m: &TraitItemMethod) -> Vec<&Attribute> {
+    m.attrs
+        .iter()
+        .filter(|attr| {
+            attr.path.is_ident("doc")
+                || attr.path.is_ident("cfg")
+                || attr.path.is_ident("deprecated")
+        })
+        .collect()
+}
+
 /// annotate the service trait with `object` this will
 /// generate a usable server and client stubs.
 /// it accepts
 /// - name [optional] default to trait name
 /// - version [optional] default to 1.0
 /// - module [optional] default to None
+/// - concurrency [optional] max number of calls to the object at the same time (`concurrency = 1`
+///   serializes the calls), default to the number of module workers
+/// - leader [optional] the object only runs on the leader replica of the module (`#[object(leader)]`),
+///   see `Server::leader`
 ///
 /// NOTE:
 /// - only trait methods with first argument as receiver will be available for RPC
 /// - receiver must be a shared ref to self (&self)
 /// - all input arguments must be of type <T: Serialize>
+/// - arguments can be borrowed (`&str`, `&[u8]` or `&T`). `&str` and `&[u8]` are decoded without
+///   copying when possible (the request uses the msgpack codec)
 /// - return must be a Result (any Result) as long as the E type can be stringfied <E: Display>
+/// - if the return type is an alias of Result (for example `MyResult<T>`) the method must be annotated
+///   with #[returns(T)] so the stub knows the type of the output
+/// - traits can be generic over types and have associated types. The Object is then generic over
+///   the implementation and the trait type parameters (`[Name]Object<T, A, B>`), while the stub is generic
+///   over the trait type parameters followed by the associated types (`[Name]Stub<A, B, Assoc>`).
+///   Lifetime and const parameters are not supported
 /// - `module` is only used to generate the stub. if provided there will be a From<Client> implementation to create
 ///   the stub directly from the client since the module name will be known
 ///
@@ -134,6 +632,14 @@ fn is_stream(m: &TraitItemMethod) -> bool {
 ///
 /// `let stub = [Name]Stub::new("module", client);`
 ///
+/// or to the latest live version of the object that is compatible with a version requirement
+///
+/// `let stub = [Name]Stub::compatible("module", client, "^1").await?;`
+///
+/// attributes on trait methods (docs, `#[cfg]`, `#[deprecated]`, lints, ...) are preserved, only the
+/// helper attributes of the macro are removed. docs, `#[cfg]` and `#[deprecated]` are also copied to the
+/// generated stub methods.
+///
 /// for compatibility with other languages (for example Golang) there is a helper attribute
 /// #[rename("new_name")] that can be added on method to rename the method. Since rust uses
 /// snake_case, while Go uses CamelCase. rename is needed if method will be used across languages
@@ -150,19 +656,85 @@ fn is_stream(m: &TraitItemMethod) -> bool {
 /// The stream functions doesn't have to return since it is spawned in it's own routing, hence when
 /// streams needed the implementation of the trait need to be Clone (self need to be Clone).
 ///
+/// If a producer returns or panics the stream is stopped. A restart policy can be set with
+/// `#[stream(restart = "on-panic")]` or `#[stream(restart = "always")]` to restart the producer
+/// (with backoff) instead. The health of the streams is reported in the module info
+/// (see `Client::introspect`).
+///
+/// Events are buffered (5 by default) while they are published. If the buffer is full `send` waits
+/// for room, this can be changed with `#[stream(buffer = 100, overflow = "drop-oldest")]` where
+/// overflow is one of "block", "drop-oldest", "drop-newest" or "coalesce-latest". The number of
+/// dropped events is reported by `sender.dropped()` and in the module info.
+///
+/// If the module runs many replicas, a stream that must run once per module (for example a producer that
+/// polls a shared resource) can be marked with `#[stream(leader)]`. It only runs on the leader replica and
+/// moves to another replica if the leader is gone. Streams with subscription parameters can't be marked.
+///
+/// Producers are started with the server even if no client is subscribed. Expensive producers can use
+/// `sender.is_observed()` or `sender.wait_for_subscribers().await` to idle while nobody is listening.
+///
+/// Streams can also take subscription parameters before the sender
+///
+/// ```example
+///   #[stream]
+///   async fn logs(&self, service: String, Sender<Line>);
+/// ```
+/// each distinct set of parameters gets its own channel. The stream is started when the first client
+/// subscribes to it, and stopped (the future is dropped) once it has no more subscribers. The stub
+/// method takes the same parameters. Parameters must be owned types (not references) that implement Clone.
+///
+/// Calls (not streams) can take a `rbus::server::Stream<T>` argument to receive a sequence of items from the
+/// client while the call runs (client-streaming). The stub then takes any `Stream<Item = T>` in its place
+///
+/// ```example
+///   async fn store(&self, name: String, chunks: Stream<Chunk>) -> Result<u64>;
+/// ```
+/// A call that also takes a `Sender<T>` argument is bidirectional, the handler sends items back to the client
+/// while it receives. The stub doesn't take the Stream and Sender arguments, instead it returns a
+/// `rbus::client::Session<In, Out, R>` to send and receive items, and finish the call to get the result.
+///
+/// ```example
+///   async fn shell(&self, input: Stream<Line>, output: Sender<Line>) -> Result<i32>;
+/// ```
+/// such calls must be async, and can take at most one Stream and one Sender.
+///
+/// A call can also stream its results to the caller by returning `Result<rbus::server::Items<T>>`. The items are
+/// pushed to the caller one by one as they are produced, and the stub returns a `rbus::client::Receiver<T>`
+/// that ends after the last item (or the error). Unlike streams, the items are private to the caller.
+///
+/// ```example
+///   async fn list(&self, prefix: String) -> Result<Items<Entry>>;
+/// ```
+///
+/// The number of concurrent calls to a single method can be limited with `#[concurrency(N)]`, for example
+/// to serialize writes while reads run in parallel. The limits can be overridden with `Server::register_with`.
+///
+/// Calls to a method can be given a priority with `#[priority("high")]` (`high`, `normal` or `low`), for
+/// example control calls that shouldn't wait behind bulk calls. Other methods use the priority of the stub
+/// (`stub.with_priority(..)`), normal by default.
+///
+/// Calls to a method marked with `#[idempotent]` can be retried by the client (see `Client::with_retry`). Each
+/// call carries an idempotency key, the server runs the call once and answers its retries with the same
+/// response. Such calls can't take Stream or Sender arguments or return Items.
+///
 ///
 #[proc_macro_attribute]
 pub fn object(args: TokenStream, input: TokenStream) -> TokenStream {
     let args = parse_macro_input!(args as AttributeArgs);
-
     let input = parse_macro_input!(input as ItemTrait);
+
+    expand(args, input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+fn expand(args: AttributeArgs, input: ItemTrait) -> syn::Result<TokenStream2> {
+    check_generics(&input.generics)?;
     let mut cleaned = input.clone();
 
     for item in cleaned.items.iter_mut() {
         if let TraitItem::Method(ref mut method) = item {
-            //TODO: this removes ALL attributes on the
-            //method. May be we should only remove the rename attribute
-            method.attrs = vec![];
+            method.attrs.retain(|attr| !is_rbus_attr(attr));
         }
     }
 
@@ -174,136 +746,466 @@ pub fn object(args: TokenStream, input: TokenStream) -> TokenStream {
     let mut name_lit = Lit::Str(LitStr::new(&name, name_id.span()));
     let mut version_lit = Lit::Str(LitStr::new("1.0", name_id.span()));
     let mut module_lit = None;
+    let mut concurrency = None;
+    let mut leader = false;
     for arg in args {
-        if let NestedMeta::Meta(Meta::NameValue(value)) = arg {
-            if value.path.is_ident("name") {
-                name_lit = value.lit;
-            } else if value.path.is_ident("version") {
-                version_lit = value.lit;
-            } else if value.path.is_ident("module") {
-                module_lit = Some(value.lit);
+        let value = match arg {
+            NestedMeta::Meta(Meta::NameValue(value)) => value,
+            NestedMeta::Meta(Meta::Path(path)) if path.is_ident("leader") => {
+                leader = true;
+                continue;
+            }
+            arg => {
+                return Err(syn::Error::new_spanned(
+                    arg,
+                    "expected `name = \"..\"`, `version = \"..\"`, `module = \"..\"`, `concurrency = N` or `leader`",
+                ))
             }
+        };
+
+        if value.path.is_ident("concurrency") {
+            concurrency = Some(concurrency_limit(&value.lit)?);
+            continue;
+        }
+
+        if !matches!(value.lit, Lit::Str(_)) {
+            return Err(syn::Error::new_spanned(
+                &value.lit,
+                "value must be a string literal",
+            ));
+        }
+
+        if value.path.is_ident("name") {
+            name_lit = value.lit;
+        } else if value.path.is_ident("version") {
+            version_lit = value.lit;
+        } else if value.path.is_ident("module") {
+            module_lit = Some(value.lit);
+        } else {
+            return Err(syn::Error::new_spanned(
+                &value.path,
+                "unknown argument, expected `name`, `version`, `module`, `concurrency` or `leader`",
+            ));
         }
     }
 
-    let functions: Vec<&TraitItem> = input
-    .items
-    .iter()
-    .filter(|item| matches!(item, TraitItem::Method(m) if !m.sig.inputs.is_empty() && matches!(m.sig.inputs[0], FnArg::Receiver(_))  && !is_stream(&m))).collect();
+    let mut functions: Vec<&TraitItemMethod> = vec![];
+    let mut streams: Vec<&TraitItemMethod> = vec![];
+    for item in input.items.iter() {
+        let method = match item {
+            TraitItem::Method(m) if matches!(m.sig.inputs.first(), Some(FnArg::Receiver(_))) => m,
+            _ => continue,
+        };
 
-    let streams: Vec<&TraitItem> = input
-    .items
-    .iter()
-    .filter(|item| matches!(item, TraitItem::Method(m) if !m.sig.inputs.is_empty() && matches!(m.sig.inputs[0], FnArg::Receiver(_))  && is_stream(&m))).collect();
+        check_receiver(method)?;
+        if is_stream(method)? {
+            let call_attr = method.attrs.iter().find(|a| {
+                a.path.is_ident("concurrency")
+                    || a.path.is_ident("priority")
+                    || a.path.is_ident("idempotent")
+            });
+            if let Some(attr) = call_attr {
+                return Err(syn::Error::new_spanned(
+                    attr,
+                    "concurrency, priority and idempotent can only be set on calls, not streams",
+                ));
+            }
+            streams.push(method);
+        } else {
+            functions.push(method);
+        }
+    }
 
-    let dispatches = functions.iter().map(|item| {
-        if let TraitItem::Method(method) = item {
+    let dispatches = functions
+        .iter()
+        .map(|method| {
             let name_id = &method.sig.ident;
-            let name_lit = method_name(&method);
-            let args = (0..method.sig.inputs.len() - 1).map(syn::Index::from);
-            let branch = if method.sig.asyncness.is_none() {
-                quote! {
-                    #name_lit => Ok(self
-                        .inner
-                        .#name_id(
-                            #( request.inputs.at(#args)?, )*
-                        )
-                        .into())
-                }
+            let name_lit = method_name(method)?;
+            let attrs = cfg_attrs(method);
+            let args = method
+                .sig
+                .inputs
+                .iter()
+                .skip(1)
+                .enumerate()
+                .map(|(i, arg)| dispatch_arg(i, arg));
+
+            let call = if method.sig.asyncness.is_none() {
+                quote! { self.inner.#name_id(#( #args, )*) }
             } else {
-                quote! {
-                    #name_lit => Ok(self
-                        .inner
-                        .#name_id(
-                            #( request.inputs.at(#args)?, )*
-                        ).await
-                        .into())
-                }
+                quote! { self.inner.#name_id(#( #args, )*).await }
             };
 
-            return branch;
-        }
+            if streams_items(method)?.is_some() {
+                return Ok(quote! {
+                    #(#attrs)*
+                    #name_lit => rbus::server::Items::reply(&request, #call).await
+                });
+            }
 
-        unreachable!();
-    });
+            Ok(quote! {
+                #(#attrs)*
+                #name_lit => Ok(rbus::protocol::Output::with_codec(
+                    request.codec(),
+                    #call,
+                ))
+            })
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    // the stub is not an implementation of the trait, so associated
+    // types are turned into type parameters of the stub.
+    let mut stub_generics = input.generics.clone();
+    for item in input.items.iter() {
+        if let TraitItem::Type(ty) = item {
+            let ident = &ty.ident;
+            let bounds = &ty.bounds;
+            stub_generics.params.push(parse_quote!(#ident: #bounds));
+        }
+    }
 
-    let stub_calls = functions.iter().map(|item| {
-        if let TraitItem::Method(method) = item {
+    let stub_calls = functions
+        .iter()
+        .map(|method| {
             let name = &method.sig.ident;
-            let name_lit = method_name(&method);
-            let inputs = method.sig.inputs.iter().skip(1);
-            let arg_names = method.sig.inputs.iter().skip(1).map(|arg| {
-                if let FnArg::Typed(a) = &arg {
-                    if let Pat::Ident(i) = a.pat.as_ref() {
-                        return &i.ident;
+            let name_lit = method_name(method)?;
+            let mut inputs: Vec<FnArg> = method.sig.inputs.iter().skip(1).cloned().collect();
+            for input in inputs.iter_mut() {
+                AssociatedTypes.visit_fn_arg_mut(input);
+            }
+            let arg_names = method
+                .sig
+                .inputs
+                .iter()
+                .skip(1)
+                .map(|arg| Ok(stub_arg(arg_ident(arg)?, arg)))
+                .collect::<syn::Result<Vec<_>>>()?;
+            let mut ret = return_inner_type(method)?;
+            AssociatedTypes.visit_type_mut(&mut ret);
+            let attrs = stub_attrs(method);
+            let pipes = call_pipes(method)?;
+            let priority = method_priority(method)?;
+            let idempotent = method_idempotent(method)?;
+            if idempotent.is_some() && (pipes.input.is_some() || pipes.output.is_some() || streams_items(method)?.is_some()) {
+                return Err(syn::Error::new_spanned(
+                    &method.sig,
+                    "idempotent calls can't take a Stream or Sender argument or return Items",
+                ));
+            }
+
+            if let Some(mut item) = streams_items(method)? {
+                if pipes.input.is_some() {
+                    return Err(syn::Error::new_spanned(
+                        &method.sig,
+                        "calls that return Items can not take a Stream argument",
+                    ));
+                }
+
+                AssociatedTypes.visit_type_mut(&mut item);
+                return Ok(quote! {
+                    #(#attrs)*
+                    pub async fn #name(&self, #(#inputs,)*) -> rbus::protocol::Result<rbus::client::Receiver<#item>> {
+                        let req = rbus::protocol::Request::with_codec(self.object.clone(), #name_lit, self.client.codec()).with_priority(#priority)
+                            #(.arg(#arg_names)?)*;
+
+                        self.client.request_items(&self.module, req).await
+                    }
+                });
+            }
+
+            if pipes.input.is_none() {
+                return Ok(quote! {
+                    #(#attrs)*
+                    pub async fn #name(&self, #(#inputs,)*) -> rbus::protocol::Result<#ret> {
+                        let req = rbus::protocol::Request::with_codec(self.object.clone(), #name_lit, self.client.codec()).with_priority(#priority)#idempotent
+                            #(.arg(#arg_names)?)*;
+
+                        let out = self.client.request(&self.module, req).await?;
+
+                        out.into()
                     }
+                });
+            }
+
+            // pipe arguments are replaced by the handles of the request pipes
+            let arg_names = method.sig.inputs.iter().skip(1).zip(arg_names).map(|(arg, name)| {
+                match pipe_arg(arg) {
+                    Some(PipeArg::Input(_)) => quote! { &__input },
+                    Some(PipeArg::Output(_)) => quote! { &__output },
+                    None => name,
                 }
-                unreachable!();
             });
-            let ret = return_inner_type(&method.sig.output).unwrap();
-            return quote! {
+
+            if let Some(mut output) = pipes.output {
+                AssociatedTypes.visit_type_mut(&mut output);
+                let mut input = pipes.input.unwrap();
+                AssociatedTypes.visit_type_mut(&mut input);
+                inputs.retain(|arg| pipe_arg(arg).is_none());
+                return Ok(quote! {
+                    #(#attrs)*
+                    pub async fn #name(&self, #(#inputs,)*) -> rbus::protocol::Result<rbus::client::Session<#input, #output, #ret>> {
+                        let req = rbus::protocol::Request::with_codec(self.object.clone(), #name_lit, self.client.codec()).with_priority(#priority);
+                        let __input = rbus::pipe::Pipe::input(&req);
+                        let __output = rbus::pipe::Pipe::output(&req);
+                        let req = req #(.arg(#arg_names)?)*;
+
+                        Ok(self.client.session(&self.module, req))
+                    }
+                });
+            }
+
+            // client-streaming call, the stub takes any stream of items
+            let mut items = None;
+            for input in inputs.iter_mut() {
+                if let FnArg::Typed(t) = input {
+                    if let Some(item) = wrapped_type(&t.ty, "Stream") {
+                        items = Some(t.pat.clone());
+                        t.ty = parse_quote!(impl rbus::client::Stream<Item = #item> + Send + 'static);
+                    }
+                }
+            }
+
+            Ok(quote! {
+                #(#attrs)*
                 pub async fn #name(&self, #(#inputs,)*) -> rbus::protocol::Result<#ret> {
-                    let req = rbus::protocol::Request::new(self.object.clone(), #name_lit)
-                        #(.arg(#arg_names)?)*;
+                    let req = rbus::protocol::Request::with_codec(self.object.clone(), #name_lit, self.client.codec()).with_priority(#priority);
+                    let __input = rbus::pipe::Pipe::input(&req);
+                    let req = req #(.arg(#arg_names)?)*;
+                    self.client.send_stream(__input, #items);
 
                     let out = self.client.request(&self.module, req).await?;
 
                     out.into()
                 }
-            };
-        }
-        unreachable!()
-    });
-    let streams_stub_calls = streams.iter().map(|item| {
-        if let TraitItem::Method(method) = item {
+            })
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    let streams_stub_calls = streams
+        .iter()
+        .map(|method| {
             let name = &method.sig.ident;
-            let name_lit = method_name(&method);
-            let ret = sender_inner_type(&method.sig.inputs[1]).unwrap();
-            return quote! {
-                pub async fn #name(&self) -> rbus::protocol::Result<rbus::client::Receiver<#ret>> {
-                    let receiver = self.client.stream(&self.module, self.object.clone(), #name_lit).await;
+            let name_lit = method_name(method)?;
+            let mut ret = sender_inner_type(stream_sender(method))?;
+            AssociatedTypes.visit_type_mut(&mut ret);
+            let attrs = stub_attrs(method);
+            let mut params: Vec<FnArg> = stream_params(method).cloned().collect();
+            if params.is_empty() {
+                return Ok(quote! {
+                    #(#attrs)*
+                    pub async fn #name(&self) -> rbus::protocol::Result<rbus::client::Receiver<#ret>> {
+                        let receiver = self.client.stream(&self.module, self.object.clone(), #name_lit).await;
 
-                    receiver
+                        receiver
+                    }
+                });
+            }
+
+            for param in params.iter_mut() {
+                AssociatedTypes.visit_fn_arg_mut(param);
+            }
+            let param_names = stream_params(method)
+                .map(arg_ident)
+                .collect::<syn::Result<Vec<_>>>()?;
+            Ok(quote! {
+                #(#attrs)*
+                pub async fn #name(&self, #(#params,)*) -> rbus::protocol::Result<rbus::client::Receiver<#ret>> {
+                    let req = rbus::protocol::Request::with_codec(self.object.clone(), #name_lit, self.client.codec())
+                        #(.arg(#param_names)?)*;
+
+                    self.client.subscribe(&self.module, req).await
                 }
-            };
-        }
-        unreachable!()
-    });
-    let streams_init = streams.iter().map(|item| {
-        if let TraitItem::Method(method) = item {
+            })
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    // the object is generic over the trait implementation (T) and
+    // all type parameters of the trait.
+    let inner = if input.generics.type_params().any(|p| p.ident == "T") {
+        format_ident!("__T")
+    } else {
+        format_ident!("T")
+    };
+    let (_, trait_ty, _) = input.generics.split_for_impl();
+    let qualified = quote! { #inner as #name_id #trait_ty };
+
+    // parameterized streams are started on subscription
+    let subscriptions = streams
+        .iter()
+        .filter(|method| stream_params(method).next().is_some())
+        .map(|method| {
             let name = &method.sig.ident;
-            let name_lit = method_name(&method);
-            return quote! {
-                let (sender, sink) = rbus::server::Sender::new();
-                let inner = self.inner.clone();
-                tokio::spawn(async move {
-                    inner.#name(sender).await;
-                });
-                sinks.insert(#name_lit.to_owned(), sink);
+            let name_lit = method_name(method)?;
+            let attrs = cfg_attrs(method);
+            let params: Vec<_> = stream_params(method)
+                .enumerate()
+                .map(|(i, _)| format_ident!("__param{}", i))
+                .collect();
+            let types = stream_params(method).map(|param| {
+                let mut ty = match param {
+                    FnArg::Typed(t) => t.ty.as_ref().clone(),
+                    FnArg::Receiver(_) => unreachable!(),
+                };
+                QualifiedSelf(qualified.clone()).visit_type_mut(&mut ty);
+                ty
+            });
+            let indexes = (0..params.len()).map(syn::Index::from);
+            let StreamOptions {
+                restart,
+                buffer,
+                overflow,
+                leader,
+            } = method_stream_options(method)?;
+            if leader {
+                return Err(syn::Error::new_spanned(
+                    &method.sig,
+                    "streams with subscription parameters can't be leader streams",
+                ));
+            }
+            Ok(quote! {
+                #(#attrs)*
+                #name_lit => {
+                    #( let #params: #types = args.at(#indexes)?; )*
+                    let (sender, mut sink) = rbus::server::Sender::with_buffer(#buffer, #overflow);
+                    let inner = self.inner.clone();
+                    sink.supervise(#name_lit, #restart, sender, move |sender| {
+                        let inner = inner.clone();
+                        #( let #params = #params.clone(); )*
+                        async move {
+                            inner.#name(#(#params,)* sender).await;
+                        }
+                    });
+                    Ok(sink)
+                }
+            })
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    // streams without parameters, and whether they only run on the leader
+    let streams_init = streams
+        .iter()
+        .filter(|method| stream_params(method).next().is_none())
+        .map(|method| {
+            let name = &method.sig.ident;
+            let name_lit = method_name(method)?;
+            let attrs = cfg_attrs(method);
+            let StreamOptions {
+                restart,
+                buffer,
+                overflow,
+                leader,
+            } = method_stream_options(method)?;
+            let init = quote! {
+                #(#attrs)*
+                {
+                    let (sender, mut sink) = rbus::server::Sender::with_buffer(#buffer, #overflow);
+                    let inner = self.inner.clone();
+                    sink.supervise(#name_lit, #restart, sender, move |sender| {
+                        let inner = inner.clone();
+                        async move {
+                            inner.#name(sender).await;
+                        }
+                    });
+                    sinks.insert(#name_lit.to_owned(), sink);
+                }
             };
+            Ok((leader, init))
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    let (leader_init, streams_init): (Vec<_>, Vec<_>) =
+        streams_init.into_iter().partition(|(leader, _)| *leader);
+    let streams_init = streams_init.into_iter().map(|(_, init)| init);
+    let leader_streams = if leader_init.is_empty() {
+        quote! {}
+    } else {
+        let leader_init = leader_init.into_iter().map(|(_, init)| init);
+        quote! {
+            fn leader_streams(&self) -> rbus::protocol::Result<std::collections::HashMap<String, rbus::server::Sink>>{
+                let mut sinks = std::collections::HashMap::default();
+                #(#leader_init)*
+                Ok(sinks)
+            }
         }
+    };
+
+    let leader = if leader {
+        quote! {
+            fn leader(&self) -> bool {
+                true
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    let method_limits = functions
+        .iter()
+        .filter_map(|method| match method_concurrency(method) {
+            Ok(Some(limit)) => Some(method_name(method).map(|name_lit| {
+                let attrs = cfg_attrs(method);
+                quote! {
+                    #(#attrs)*
+                    {
+                        concurrency = concurrency.method(#name_lit, #limit);
+                    }
+                }
+            })),
+            Ok(None) => None,
+            Err(err) => Some(Err(err)),
+        })
+        .collect::<syn::Result<Vec<_>>>()?;
 
-        unreachable!();
-    });
+    let concurrency = if concurrency.is_some() || !method_limits.is_empty() {
+        let object_limit = match concurrency {
+            Some(limit) => quote! { rbus::server::Concurrency::new(#limit) },
+            None => quote! { rbus::server::Concurrency::unlimited() },
+        };
 
-    let bounds = if streams.len() > 0 {
         quote! {
-            #name_id + Clone + Send + Sync + 'static
+            fn concurrency(&self) -> rbus::server::Concurrency {
+                #[allow(unused_mut)]
+                let mut concurrency = #object_limit;
+                #(#method_limits)*
+                concurrency
+            }
         }
     } else {
+        quote! {}
+    };
+
+    let bounds = if !streams.is_empty() {
         quote! {
-            #name_id + Send + Sync + 'static
+            #name_id #trait_ty + Clone + Send + Sync + 'static
+        }
+    } else {
+        quote! {
+            #name_id #trait_ty + Send + Sync + 'static
         }
     };
 
+    let mut object_generics = input.generics.clone();
+    object_generics.params.insert(0, parse_quote!(#inner));
+    object_generics
+        .make_where_clause()
+        .predicates
+        .push(parse_quote!(#inner: #bounds));
+    let (object_impl, object_ty, object_where) = object_generics.split_for_impl();
+    let object_phantom = phantom(&input.generics);
+
+    let (stub_impl, stub_ty, stub_where) = stub_generics.split_for_impl();
+    let stub_phantom = phantom(&stub_generics);
+
     let stub_from = match module_lit {
         Some(module) => quote! {
-            impl From<rbus::client::Client> for #name_stub {
-                fn from(client: rbus::client::Client) -> #name_stub {
+            impl #stub_impl From<rbus::client::Client> for #name_stub #stub_ty #stub_where {
+                fn from(client: rbus::client::Client) -> Self {
                     #name_stub {
                         module: #module.into(),
                         client,
                         object: rbus::protocol::ObjectID::new(#name_lit, #version_lit),
+                        priority: rbus::protocol::Priority::Normal,
+                        phantom: std::marker::PhantomData,
                     }
                 }
             }
@@ -313,23 +1215,22 @@ pub fn object(args: TokenStream, input: TokenStream) -> TokenStream {
     };
 
     let vis = &input.vis;
-    let output = quote! {
+    Ok(quote! {
         #[allow(non_snake_case)]
 
         #[async_trait::async_trait]
         #cleaned
 
-        #vis struct #name_object<T>
-        where
-            T: #bounds,
+        #vis struct #name_object #object_generics #object_where
         {
-            inner: T,
+            inner: #inner,
+            phantom: #object_phantom,
         }
 
+        // the object calls deprecated methods on behalf of the remote callers
+        #[allow(deprecated)]
         #[async_trait::async_trait]
-        impl<T> rbus::server::Object for #name_object<T>
-        where
-            T: #bounds,
+        impl #object_impl rbus::server::Object for #name_object #object_ty #object_where
         {
             fn id(&self) -> rbus::protocol::ObjectID {
                 rbus::protocol::ObjectID::new(#name_lit, #version_lit)
@@ -348,38 +1249,86 @@ pub fn object(args: TokenStream, input: TokenStream) -> TokenStream {
                 #(#streams_init)*
                 Ok(sinks)
             }
+
+            fn stream(&self, name: &str, args: &rbus::protocol::Tuple) -> rbus::protocol::Result<rbus::server::Sink> {
+                match name {
+                    #(#subscriptions,)*
+
+                    _ => Err(rbus::protocol::Error::UnknownStream(name.into())),
+                }
+            }
+
+            #concurrency
+
+            #leader
+
+            #leader_streams
         }
 
-        impl<T> From<T> for #name_object<T>
-        where
-            T: #bounds,
+        impl #object_impl From<#inner> for #name_object #object_ty #object_where
         {
-            fn from(inner: T) -> Self {
-                Self { inner }
+            fn from(inner: #inner) -> Self {
+                Self {
+                    inner,
+                    phantom: std::marker::PhantomData,
+                }
             }
         }
 
-        #vis struct #name_stub {
+        #vis struct #name_stub #stub_generics #stub_where {
             module: String,
             client: rbus::client::Client,
             object: rbus::protocol::ObjectID,
+            priority: rbus::protocol::Priority,
+            phantom: #stub_phantom,
         }
 
-        impl #name_stub {
-            pub fn new<S: Into<String>>(module: S, client: rbus::client::Client) -> #name_stub {
+        impl #stub_impl #name_stub #stub_ty #stub_where {
+            pub fn new<S: Into<String>>(module: S, client: rbus::client::Client) -> Self {
                 #name_stub {
                     module: module.into(),
                     client,
                     object: rbus::protocol::ObjectID::new(#name_lit, #version_lit),
+                    priority: rbus::protocol::Priority::Normal,
+                    phantom: std::marker::PhantomData,
                 }
             }
 
+            /// create a stub to the latest live version of the object that matches
+            /// the version requirement (for example "^1")
+            pub async fn compatible<S, R>(module: S, client: rbus::client::Client, req: R) -> rbus::protocol::Result<Self>
+            where
+                S: Into<String>,
+                R: AsRef<str>,
+            {
+                let module = module.into();
+                let object = client.resolve(&module, #name_lit, req).await?;
+                Ok(#name_stub {
+                    module,
+                    client,
+                    object,
+                    priority: rbus::protocol::Priority::Normal,
+                    phantom: std::marker::PhantomData,
+                })
+            }
+
+            /// target a named instance of the object
+            pub fn with_instance<S: Into<String>>(mut self, instance: S) -> Self {
+                self.object.instance = Some(instance.into());
+                self
+            }
+
+            /// set the priority of the calls made by this stub. Methods that
+            /// declare a #[priority] keep their own priority
+            pub fn with_priority(mut self, priority: rbus::protocol::Priority) -> Self {
+                self.priority = priority;
+                self
+            }
+
             #(#stub_calls)*
             #(#streams_stub_calls)*
         }
 
         #stub_from
-    };
-
-    output.into()
+    })
 }
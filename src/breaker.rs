@@ -0,0 +1,243 @@
+//! Circuit breaker of the client.
+use crate::introspect::INFO_INTERVAL;
+use crate::retry::Failure;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Circuit breaker policy of the client. The client keeps a circuit per module and
+/// object. A circuit trips (opens) after a number of consecutive timeouts, or if
+/// discovery is enabled and the module is not live (no server publishes its info, see
+/// [`crate::introspect`]). Calls
+/// to an open circuit fail right away with [`crate::protocol::Error::CircuitOpen`]
+/// instead of piling up in the module queue.
+///
+/// Once the cooldown is over, one call is let through as a probe. The circuit closes
+/// if the probe gets a response, otherwise it opens again for another cooldown.
+///
+/// Timeouts need a client timeout (see [`crate::Client::with_timeout`]).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breaker {
+    timeouts: usize,
+    cooldown: Duration,
+    discovery: bool,
+}
+
+impl Default for Breaker {
+    fn default() -> Self {
+        Breaker::new(5, Duration::from_secs(30))
+    }
+}
+
+impl Breaker {
+    /// trip after timeouts consecutive timeouts, and stay open for cooldown
+    pub fn new(timeouts: usize, cooldown: Duration) -> Breaker {
+        assert!(timeouts >= 1, "timeouts must be at least 1");
+        Breaker {
+            timeouts,
+            cooldown,
+            discovery: false,
+        }
+    }
+
+    /// check that the module is live before sending calls (disabled by default). Only
+    /// enable it if all the servers of the module publish their info: other zbus
+    /// implementations don't, and rbus servers only do after their first update.
+    pub fn discovery(mut self, enabled: bool) -> Breaker {
+        self.discovery = enabled;
+        self
+    }
+}
+
+/// what to do with a call
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Admit {
+    /// send the call
+    Pass,
+    /// check that the module is live before sending the call
+    Check,
+    /// fail the call, the circuit is open
+    Reject,
+}
+
+enum State {
+    Closed { timeouts: usize },
+    Open { since: Instant },
+    // a probe is running since
+    HalfOpen { since: Instant },
+}
+
+struct Circuit {
+    state: State,
+    // last time the module was checked
+    checked: Option<Instant>,
+}
+
+/// the circuits of a client, by module and object
+pub(crate) struct Circuits {
+    breaker: Breaker,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl From<Breaker> for Circuits {
+    fn from(breaker: Breaker) -> Self {
+        Circuits {
+            breaker,
+            circuits: Mutex::default(),
+        }
+    }
+}
+
+impl Circuits {
+    fn with<F, T>(&self, key: &str, f: F) -> T
+    where
+        F: FnOnce(&mut Circuit) -> T,
+    {
+        let mut circuits = self.circuits.lock().unwrap_or_else(PoisonError::into_inner);
+        let circuit = circuits.entry(key.into()).or_insert(Circuit {
+            state: State::Closed { timeouts: 0 },
+            checked: None,
+        });
+
+        f(circuit)
+    }
+
+    /// decide if a call on circuit key can be sent now. The module is checked at most
+    /// once per info interval while the circuit is closed, and before each probe.
+    pub(crate) fn admit(&self, key: &str, now: Instant) -> Admit {
+        let breaker = &self.breaker;
+        let check = if breaker.discovery {
+            Admit::Check
+        } else {
+            Admit::Pass
+        };
+
+        self.with(key, |circuit| match circuit.state {
+            State::Closed { .. } => {
+                let due = circuit
+                    .checked
+                    .map(|checked| now.duration_since(checked) >= INFO_INTERVAL)
+                    .unwrap_or(true);
+                if !due {
+                    return Admit::Pass;
+                }
+
+                circuit.checked = Some(now);
+                check
+            }
+            // a probe that hangs (no client timeout) doesn't block the circuit forever
+            State::Open { since } | State::HalfOpen { since }
+                if now.duration_since(since) >= breaker.cooldown =>
+            {
+                log::debug!("probing circuit '{}'", key);
+                circuit.state = State::HalfOpen { since: now };
+                circuit.checked = Some(now);
+                check
+            }
+            State::Open { .. } | State::HalfOpen { .. } => Admit::Reject,
+        })
+    }
+
+    /// open circuit key, the module is not live
+    pub(crate) fn trip(&self, key: &str, now: Instant) {
+        self.with(key, |circuit| {
+            if matches!(circuit.state, State::Closed { .. }) {
+                log::warn!("circuit '{}' is open", key);
+            }
+
+            circuit.state = State::Open { since: now };
+        })
+    }
+
+    /// record the result of a call on circuit key, failure is None if the call got
+    /// a response (even if the call failed)
+    pub(crate) fn record(&self, key: &str, failure: Option<Failure>, now: Instant) {
+        let breaker = &self.breaker;
+        self.with(key, |circuit| {
+            circuit.state = match (&circuit.state, failure) {
+                (State::Closed { .. }, None) => State::Closed { timeouts: 0 },
+                (_, None) => {
+                    log::info!("circuit '{}' is closed", key);
+                    State::Closed { timeouts: 0 }
+                }
+                (State::Closed { timeouts }, Some(Failure::Timeout))
+                    if timeouts + 1 < breaker.timeouts =>
+                {
+                    State::Closed {
+                        timeouts: timeouts + 1,
+                    }
+                }
+                (State::Closed { timeouts }, Some(Failure::Timeout)) => {
+                    log::warn!("circuit '{}' is open after {} timeouts", key, timeouts + 1);
+                    State::Open { since: now }
+                }
+                // other failures (redis errors) don't tell if the module is up
+                (State::Closed { timeouts }, Some(_)) => State::Closed {
+                    timeouts: *timeouts,
+                },
+                // the probe failed
+                (State::HalfOpen { .. }, Some(_)) => State::Open { since: now },
+                (State::Open { since }, Some(_)) => State::Open { since: *since },
+            };
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn circuits() {
+        let circuits = Circuits::from(Breaker::new(2, Duration::from_secs(10)).discovery(true));
+        let now = Instant::now();
+        let key = "module.object@1.0";
+
+        // the module is checked once per interval
+        assert_eq!(circuits.admit(key, now), Admit::Check);
+        assert_eq!(circuits.admit(key, now), Admit::Pass);
+        assert_eq!(circuits.admit(key, now + INFO_INTERVAL), Admit::Check);
+
+        // a response resets the timeouts
+        circuits.record(key, Some(Failure::Timeout), now);
+        circuits.record(key, None, now);
+        circuits.record(key, Some(Failure::Timeout), now);
+        circuits.record(key, Some(Failure::Connection), now);
+        assert_eq!(circuits.admit(key, now), Admit::Pass);
+
+        // consecutive timeouts trip the circuit
+        circuits.record(key, Some(Failure::Timeout), now);
+        assert_eq!(circuits.admit(key, now), Admit::Reject);
+        assert_eq!(circuits.admit("module.other@1.0", now), Admit::Check);
+
+        // one probe after the cooldown, a failed probe opens the circuit again
+        let later = now + Duration::from_secs(10);
+        assert_eq!(circuits.admit(key, later), Admit::Check);
+        assert_eq!(circuits.admit(key, later), Admit::Reject);
+        circuits.record(key, Some(Failure::Timeout), later);
+        assert_eq!(
+            circuits.admit(key, later + Duration::from_secs(5)),
+            Admit::Reject
+        );
+
+        // a successful probe closes the circuit
+        let later = later + Duration::from_secs(10);
+        assert_eq!(circuits.admit(key, later), Admit::Check);
+        circuits.record(key, None, later);
+        assert_eq!(circuits.admit(key, later), Admit::Pass);
+
+        // the module is gone
+        circuits.trip(key, later);
+        assert_eq!(circuits.admit(key, later), Admit::Reject);
+
+        // without discovery (the default) the module is never checked, and the
+        // probe is sent right away
+        let circuits = Circuits::from(Breaker::new(2, Duration::from_secs(10)));
+        assert_eq!(circuits.admit(key, now), Admit::Pass);
+        circuits.record(key, Some(Failure::Timeout), now);
+        circuits.record(key, Some(Failure::Timeout), now);
+        assert_eq!(circuits.admit(key, now), Admit::Reject);
+        let later = now + Duration::from_secs(10);
+        assert_eq!(circuits.admit(key, later), Admit::Pass);
+    }
+}
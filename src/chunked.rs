@@ -0,0 +1,442 @@
+//! Chunked transfer of large payloads.
+//!
+//! Redis values (and memory) limit how big a single request or response can be. Large
+//! payloads are instead split in chunks that are pushed to a side key (a redis list)
+//! and reassembled on the other end.
+//!
+//! This is used in two ways:
+//! - transparently, when chunking is enabled on the client ([`crate::Client::with_chunking`])
+//!   inputs and outputs that are larger than the chunk size are moved to side keys.
+//! - explicitly, with a [`Blob`] which is a handle to a side key that can be passed as an
+//!   argument or returned from a method. Data of a blob is streamed, so files can be
+//!   transferred without buffering the whole thing in memory.
+//!
+//! ```ignore
+//! // client side
+//! let file = tokio::fs::File::open("some-file").await?;
+//! let blob = client.upload(file);
+//! stub.store("name", blob).await?;
+//!
+//! // server side (handler)
+//! async fn store(&self, name: String, blob: Blob) -> Result<()> {
+//!     let mut reader = blob.open()?;
+//!     let mut file = tokio::fs::File::create(name).await?;
+//!     tokio::io::copy(&mut reader, &mut file).await?;
+//!     Ok(())
+//! }
+//! ```
+use crate::protocol::{self, Codec, Error, Output, Request, Result};
+use bb8_redis::{bb8::Pool, redis::AsyncCommands, RedisConnectionManager};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration, Instant};
+
+/// default size of a single chunk
+pub const CHUNK_SIZE: usize = 512 * 1024;
+/// max number of chunks that can wait in a side key before the writer
+/// waits for the reader to catch up
+pub(crate) const MAX_PENDING: usize = 8;
+/// how long (in seconds) to wait for the next chunk
+const PULL_TIMEOUT: usize = 60;
+/// side keys expire if not consumed
+const CHUNKS_TTL: usize = 5 * 60;
+
+tokio::task_local! {
+    static POOL: Pool<RedisConnectionManager>;
+}
+
+/// run the future f with access to the redis pool. Blobs used inside
+/// f (handlers) will use this pool.
+pub(crate) async fn scope<F: Future>(pool: Pool<RedisConnectionManager>, f: F) -> F::Output {
+    POOL.scope(pool, f).await
+}
+
+pub(crate) fn pool() -> Result<Pool<RedisConnectionManager>> {
+    POOL.try_with(|pool| pool.clone())
+        .map_err(|_| Error::Protocol("blob can only be used from inside a call handler".into()))
+}
+
+/// Frame sent over a side key
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum Frame {
+    Data(ByteBuf),
+    End,
+    Error(String),
+}
+
+/// push a frame to side key. returns the number of frames waiting in the key.
+pub(crate) async fn push(
+    pool: &Pool<RedisConnectionManager>,
+    key: &str,
+    frame: &Frame,
+) -> Result<usize> {
+    let mut con = pool
+        .get()
+        .await
+        .map_err(|err| Error::Protocol(format!("failed to get redis connection: {}", err)))?;
+
+    let frame = protocol::encode(frame)?;
+    let pending: usize = con
+        .rpush(key, frame.into_vec())
+        .await
+        .map_err(|err| Error::Protocol(format!("failed to push chunk: {}", err)))?;
+    let _ = con.expire::<_, ()>(key, CHUNKS_TTL).await;
+
+    Ok(pending)
+}
+
+/// pull next frame from side key
+pub(crate) async fn pull(pool: &Pool<RedisConnectionManager>, key: &str) -> Result<Frame> {
+    pull_timeout(pool, key, PULL_TIMEOUT).await
+}
+
+/// pull next frame from side key, waiting at most timeout (in seconds)
+pub(crate) async fn pull_timeout(
+    pool: &Pool<RedisConnectionManager>,
+    key: &str,
+    timeout: usize,
+) -> Result<Frame> {
+    let mut con = pool
+        .get()
+        .await
+        .map_err(|err| Error::Protocol(format!("failed to get redis connection: {}", err)))?;
+
+    let frame: Option<(String, Vec<u8>)> = con
+        .blpop(key, timeout)
+        .await
+        .map_err(|err| Error::Protocol(format!("failed to pull chunk: {}", err)))?;
+
+    match frame {
+        Some((_, frame)) => Codec::MsgPack.decode(&frame),
+        None => Err(Error::Protocol(format!(
+            "timeout waiting for chunks of '{}'",
+            key
+        ))),
+    }
+}
+
+/// wait until the number of frames waiting in key drops below MAX_PENDING
+pub(crate) async fn drain(pool: &Pool<RedisConnectionManager>, key: &str) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(PULL_TIMEOUT as u64);
+    loop {
+        sleep(Duration::from_millis(100)).await;
+        let mut con = pool
+            .get()
+            .await
+            .map_err(|err| Error::Protocol(format!("failed to get redis connection: {}", err)))?;
+
+        let pending: usize = con
+            .llen(key)
+            .await
+            .map_err(|err| Error::Protocol(format!("failed to check chunks: {}", err)))?;
+
+        if pending < MAX_PENDING {
+            return Ok(());
+        }
+
+        if Instant::now() > deadline {
+            return Err(Error::Protocol(format!(
+                "timeout waiting for chunks of '{}' to be consumed",
+                key
+            )));
+        }
+    }
+}
+
+/// split data in chunks of size and push them to key
+async fn split(
+    pool: &Pool<RedisConnectionManager>,
+    key: &str,
+    data: &[u8],
+    size: usize,
+) -> Result<()> {
+    for chunk in data.chunks(size.max(1)) {
+        push(pool, key, &Frame::Data(ByteBuf::from(chunk))).await?;
+    }
+
+    push(pool, key, &Frame::End).await?;
+    Ok(())
+}
+
+/// read all chunks from key and join them
+async fn join(pool: &Pool<RedisConnectionManager>, key: &str) -> Result<ByteBuf> {
+    let mut data = vec![];
+    loop {
+        match pull(pool, key).await? {
+            Frame::Data(chunk) => data.extend_from_slice(&chunk),
+            Frame::End => return Ok(ByteBuf::from(data)),
+            Frame::Error(err) => return Err(Error::Protocol(err)),
+        }
+    }
+}
+
+fn new_key() -> String {
+    format!("chunks.{}", uuid::Uuid::new_v4())
+}
+
+fn side_key(value: &[u8]) -> Result<&str> {
+    std::str::from_utf8(value).map_err(|_| Error::Protocol("invalid chunks key".into()))
+}
+
+/// move all request inputs larger than size to side keys
+pub(crate) async fn split_request(
+    pool: &Pool<RedisConnectionManager>,
+    request: &mut Request,
+    size: usize,
+) -> Result<()> {
+    let mut inputs = vec![];
+    for (i, value) in request.inputs.values_mut().iter_mut().enumerate() {
+        if value.len() <= size {
+            continue;
+        }
+
+        let key = new_key();
+        split(pool, &key, value, size).await?;
+        *value = ByteBuf::from(key.into_bytes());
+        inputs.push(i);
+    }
+
+    request.chunking = Some(protocol::Chunking { size, inputs });
+    Ok(())
+}
+
+/// join all chunked request inputs
+pub(crate) async fn join_request(
+    pool: &Pool<RedisConnectionManager>,
+    request: &mut Request,
+) -> Result<()> {
+    let inputs = match request.chunking.as_mut() {
+        Some(chunking) => std::mem::take(&mut chunking.inputs),
+        None => return Ok(()),
+    };
+
+    for i in inputs {
+        let value = request
+            .inputs
+            .values_mut()
+            .get_mut(i)
+            .ok_or(Error::ArgumentOutOfRange(i))?;
+
+        *value = join(pool, side_key(value)?).await?;
+    }
+
+    request.decompress()
+}
+
+/// move output data to a side key if it's larger than size
+pub(crate) async fn split_output(
+    pool: &Pool<RedisConnectionManager>,
+    output: &mut Output,
+    size: usize,
+) -> Result<()> {
+    if output.chunked || output.data.len() <= size {
+        return Ok(());
+    }
+
+    let key = new_key();
+    split(pool, &key, &output.data, size).await?;
+    output.data = ByteBuf::from(key.into_bytes());
+    output.chunked = true;
+
+    Ok(())
+}
+
+/// join output data if it was chunked
+pub(crate) async fn join_output(
+    pool: &Pool<RedisConnectionManager>,
+    output: &mut Output,
+) -> Result<()> {
+    if !output.chunked {
+        return Ok(());
+    }
+
+    output.data = join(pool, side_key(&output.data)?).await?;
+    output.chunked = false;
+
+    output.decompress()
+}
+
+/// Blob is a handle to a stream of bytes stored in a side key. It can be
+/// used as a method argument or output to transfer large data (files) without
+/// buffering it in memory.
+///
+/// A blob can only be read once.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Blob {
+    #[serde(rename = "Key")]
+    key: String,
+}
+
+impl Blob {
+    /// start streaming data from reader to a new blob. Must be called from
+    /// inside a call handler, use [`crate::Client::upload`] on the client side.
+    pub fn from_reader<R>(reader: R) -> Result<Blob>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        Ok(Self::upload(pool()?, reader))
+    }
+
+    /// open the blob for reading. Must be called from inside a call
+    /// handler, use [`crate::Client::open`] on the client side.
+    pub fn open(&self) -> Result<ChunkedReader> {
+        Ok(self.reader(pool()?))
+    }
+
+    pub(crate) fn upload<R>(pool: Pool<RedisConnectionManager>, reader: R) -> Blob
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        let blob = Blob { key: new_key() };
+        let key = blob.key.clone();
+        tokio::spawn(async move {
+            if let Err(err) = copy(&pool, &key, reader).await {
+                log::error!("failed to upload blob '{}': {}", key, err);
+                let _ = push(&pool, &key, &Frame::Error(err.to_string())).await;
+            }
+        });
+
+        blob
+    }
+
+    pub(crate) fn reader(&self, pool: Pool<RedisConnectionManager>) -> ChunkedReader {
+        let (tx, rx) = mpsc::channel(1);
+        let key = self.key.clone();
+        tokio::spawn(async move {
+            loop {
+                let chunk = match pull(&pool, &key).await {
+                    Ok(Frame::Data(chunk)) => Ok(chunk),
+                    Ok(Frame::End) => break,
+                    Ok(Frame::Error(err)) => Err(io::Error::other(err)),
+                    Err(err) => Err(io::Error::other(err)),
+                };
+
+                let failed = chunk.is_err();
+                if tx.send(chunk).await.is_err() || failed {
+                    break;
+                }
+            }
+
+            if let Ok(mut con) = pool.get().await {
+                let _ = con.del::<_, ()>(&key).await;
+            }
+        });
+
+        ChunkedReader::new(rx)
+    }
+}
+
+/// copy all data from reader to key in chunks
+async fn copy<R>(pool: &Pool<RedisConnectionManager>, key: &str, mut reader: R) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        (&mut reader)
+            .take(CHUNK_SIZE as u64)
+            .read_to_end(&mut chunk)
+            .await
+            .map_err(|err| Error::Protocol(format!("failed to read data: {}", err)))?;
+
+        if chunk.is_empty() {
+            break;
+        }
+
+        let last = chunk.len() < CHUNK_SIZE;
+        if push(pool, key, &Frame::Data(ByteBuf::from(chunk))).await? >= MAX_PENDING {
+            drain(pool, key).await?;
+        }
+
+        if last {
+            break;
+        }
+    }
+
+    push(pool, key, &Frame::End).await?;
+    Ok(())
+}
+
+/// ChunkedReader reads the content of a [`Blob`]
+pub struct ChunkedReader {
+    rx: mpsc::Receiver<io::Result<ByteBuf>>,
+    buf: ByteBuf,
+    pos: usize,
+}
+
+impl ChunkedReader {
+    fn new(rx: mpsc::Receiver<io::Result<ByteBuf>>) -> Self {
+        Self {
+            rx,
+            buf: ByteBuf::default(),
+            pos: 0,
+        }
+    }
+}
+
+impl AsyncRead for ChunkedReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.pos < self.buf.len() {
+                let n = out.remaining().min(self.buf.len() - self.pos);
+                let start = self.pos;
+                out.put_slice(&self.buf[start..start + n]);
+                self.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    self.buf = chunk;
+                    self.pos = 0;
+                }
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Err(err)),
+                // end of stream
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn reader() {
+        let (tx, rx) = mpsc::channel(3);
+        let mut reader = ChunkedReader::new(rx);
+
+        tx.send(Ok(ByteBuf::from("hello "))).await.unwrap();
+        tx.send(Ok(ByteBuf::from(""))).await.unwrap();
+        tx.send(Ok(ByteBuf::from("world"))).await.unwrap();
+        drop(tx);
+
+        let mut data = String::new();
+        reader.read_to_string(&mut data).await.unwrap();
+        assert_eq!(data, "hello world");
+    }
+
+    #[tokio::test]
+    async fn reader_error() {
+        let (tx, rx) = mpsc::channel(2);
+        let mut reader = ChunkedReader::new(rx);
+
+        tx.send(Ok(ByteBuf::from("hello"))).await.unwrap();
+        tx.send(Err(io::Error::other("broken"))).await.unwrap();
+
+        let mut data = vec![];
+        assert!(reader.read_to_end(&mut data).await.is_err());
+    }
+}
@@ -1,55 +1,106 @@
-use crate::protocol::{Error, ObjectID, Output, Request, Response, Result};
+use crate::breaker::{Admit, Circuits};
+use crate::chunked::{self, Blob, ChunkedReader};
+use crate::introspect::{self, ModuleInfo};
+use crate::pipe::{self, Pipe};
+use crate::protocol::{Codec, Error, Kind, ObjectID, Output, Request, Response, Result};
 use anyhow::Context;
 use bb8_redis::{
     bb8::Pool,
     redis::{AsyncCommands, ConnectionInfo, IntoConnectionInfo},
     RedisConnectionManager,
 };
+pub use futures_util::Stream;
+use futures_util::{stream, StreamExt};
 use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_bytes::ByteBuf;
+use std::future::Future;
 use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{self, Poll};
+use thiserror::Error;
+use tokio::io::AsyncRead;
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant, Sleep};
+
+pub use crate::breaker::Breaker;
+pub use crate::retry::{Failure, Retry};
 
 /// Receiver is returned by the stream method of the client. Used to subscribe to events.
+/// It's also returned by calls that stream their results, in which case it ends
+/// after the last item (or the call error).
+///
+/// Receiver is also a [`Stream`] of events, so it can be used with `StreamExt` combinators
+/// and in `select!`.
 pub struct Receiver<T> {
-    rx: mpsc::Receiver<serde_bytes::ByteBuf>,
-    p: PhantomData<T>,
+    rx: mpsc::Receiver<Result<ByteBuf>>,
+    codec: Codec,
+    p: PhantomData<fn() -> T>,
 }
 impl<T> Receiver<T>
 where
     T: DeserializeOwned,
 {
     fn new() -> (Self, Source) {
+        Self::with_codec(Codec::MsgPack)
+    }
+
+    fn with_codec(codec: Codec) -> (Self, Source) {
         let (tx, rx) = mpsc::channel(5);
-        (Self { rx, p: PhantomData }, Source { tx })
+        let receiver = Self {
+            rx,
+            codec,
+            p: PhantomData,
+        };
+
+        (receiver, Source { tx })
+    }
+
+    fn decode(codec: Codec, received: Result<ByteBuf>) -> anyhow::Result<T> {
+        //I really think this should be unwrap because it means there is a "logic" error
+        // not just runtime error
+        Ok(codec.decode(&received?)?)
     }
 
     /// recv receives an event. return None of subscription was stopped (lost redis connection, etc..)
     /// it's up to the caller to retry subscribing to the event stream again.
     pub async fn recv(&mut self) -> Option<anyhow::Result<T>> {
         let received = self.rx.recv().await?;
-        //I really think this should be unwrap because it means there is a "logic" error
-        // not just runtime error
-        Some(rmp_serde::decode::from_read_ref(&received).map_err(|err| anyhow::anyhow!("{}", err)))
+        Some(Self::decode(self.codec, received))
+    }
+}
+
+impl<T> Stream for Receiver<T>
+where
+    T: DeserializeOwned,
+{
+    type Item = anyhow::Result<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let codec = self.codec;
+        self.rx
+            .poll_recv(cx)
+            .map(|received| received.map(|received| Self::decode(codec, received)))
     }
 }
 
 /// Source is used internally by the client to send received bytes to Receiver
 struct Source {
-    tx: mpsc::Sender<serde_bytes::ByteBuf>,
+    tx: mpsc::Sender<Result<ByteBuf>>,
 }
 
 impl Source {
-    /// send bytes to receiver
-    #[allow(dead_code)]
-    pub async fn send(&self, msg: ByteBuf) -> anyhow::Result<()> {
+    /// send bytes (or an error) to receiver
+    pub async fn send(&self, msg: Result<ByteBuf>) -> anyhow::Result<()> {
         Ok(self.tx.send(msg).await?)
     }
 
     /// send_blocking is a work around for the unstable async redis pubsub. instead we do subscribe
     /// in blocking code (thread)
     pub fn send_blocking(&self, msg: ByteBuf) -> anyhow::Result<()> {
-        Ok(self.tx.blocking_send(msg)?)
+        Ok(self.tx.blocking_send(Ok(msg))?)
     }
 }
 
@@ -60,6 +111,13 @@ impl Source {
 pub struct Client {
     info: ConnectionInfo,
     pool: Pool<RedisConnectionManager>,
+    codec: Codec,
+    compression: Option<usize>,
+    chunking: Option<usize>,
+    timeout: Option<Duration>,
+    retry: Retry,
+    // shared by the clones of the client
+    circuits: Option<Arc<Circuits>>,
 }
 
 impl Client {
@@ -71,12 +129,337 @@ impl Client {
             .build(mgr)
             .await?;
 
-        Ok(Self { info, pool })
+        Ok(Self {
+            info,
+            pool,
+            codec: Codec::default(),
+            compression: None,
+            chunking: None,
+            timeout: None,
+            retry: Retry::never(),
+            circuits: None,
+        })
+    }
+
+    /// enable compression of request inputs (and response outputs) that are
+    /// larger than threshold (in bytes). Only enable this if the server is
+    /// an rbus server since other zbus implementations can't decompress.
+    pub fn with_compression(mut self, threshold: usize) -> Self {
+        self.compression = Some(threshold);
+        self
+    }
+
+    /// set the codec used by stubs to encode requests. Default to MsgPack.
+    /// Streams and sessions are always MsgPack (see [`Codec`])
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// codec used to encode requests
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// enable chunked transfer of request inputs (and response outputs) that are
+    /// larger than size (in bytes). Large payloads are split in chunks of size and
+    /// stored in side keys. Only enable this if the server is an rbus server since
+    /// other zbus implementations don't understand chunks.
+    pub fn with_chunking(mut self, size: usize) -> Self {
+        self.chunking = Some(size);
+        self
+    }
+
+    /// fail calls with [`Error::Timeout`] if there is no response (or no item of a call
+    /// that streams its results) within timeout. The timeout is rounded up to seconds.
+    /// Note that the call might still run on the server. Calls wait forever by default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// set the retry policy of idempotent calls, see [`Retry`]. Calls are not retried
+    /// by default.
+    pub fn with_retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// fail calls fast while their module (or object) is down, see [`Breaker`]. The
+    /// clones of the client share the same circuits. Disabled by default.
+    pub fn with_breaker(mut self, breaker: Breaker) -> Self {
+        self.circuits = Some(Arc::new(breaker.into()));
+        self
+    }
+
+    /// start uploading data from reader to a blob. The blob can then be
+    /// passed as an argument to a call. Data is streamed while the server
+    /// reads the blob.
+    pub fn upload<R>(&self, reader: R) -> Blob
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        Blob::upload(self.pool.clone(), reader)
+    }
+
+    /// open a blob (usually returned by a call) for reading
+    pub fn open(&self, blob: &Blob) -> ChunkedReader {
+        blob.reader(self.pool.clone())
+    }
+
+    /// start sending the items to the pipe (usually the input pipe of a request). The
+    /// pipe is closed once all items are sent. Used by client-streaming calls.
+    pub fn send_stream<T, S>(&self, pipe: Pipe, items: S)
+    where
+        T: Serialize + Send,
+        S: Stream<Item = T> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        tokio::spawn(async move {
+            let mut items = Box::pin(items);
+            let mut result = Ok(());
+            while let Some(item) = items.next().await {
+                result = match crate::protocol::encode(&item) {
+                    Ok(item) => pipe.send(&pool, item).await,
+                    Err(err) => Err(err),
+                };
+
+                if result.is_err() {
+                    break;
+                }
+            }
+
+            pipe.close(&pool, result).await;
+        });
     }
 
-    /// make a request, and wait for response Output
-    /// TODO: a request function with deadline.
+    /// start a bidirectional call. The request must take the input and output
+    /// pipes of the request as arguments. The call runs in the background until
+    /// the session is finished.
+    pub fn session<S, I, O, R>(&self, module: S, request: Request) -> Session<I, O, R>
+    where
+        S: Into<String>,
+        O: DeserializeOwned,
+        R: DeserializeOwned,
+    {
+        let input = Pipe::input(&request);
+        let output = Pipe::output(&request);
+        let rx = output.reader(self.pool.clone());
+
+        let client = self.clone();
+        let module = module.into();
+        let call = tokio::spawn(async move {
+            let result = client.request(module, request).await;
+            if let Err(err) = &result {
+                // the handler might not even have started, make sure the
+                // receiver doesn't wait for output that will never come.
+                output
+                    .close(&client.pool, Err(Error::Protocol(err.to_string())))
+                    .await;
+            }
+
+            result
+        });
+
+        Session {
+            pool: self.pool.clone(),
+            input,
+            closed: false,
+            rx,
+            call,
+            p: PhantomData,
+        }
+    }
+
+    /// make a request, and wait for response Output. Idempotent requests are retried
+    /// according to the client retry policy.
     pub async fn request<S>(&self, module: S, request: Request) -> Result<Output>
+    where
+        S: AsRef<str>,
+    {
+        if request.idempotency_key.is_none() {
+            return self
+                .attempt(module.as_ref(), request)
+                .await
+                .map_err(|(_, err)| err);
+        }
+
+        let mut attempt = 1;
+        loop {
+            // the retries carry the same id and idempotency key, so the server
+            // runs the request once
+            let result = self.attempt(module.as_ref(), request.clone()).await;
+            let failure = match &result {
+                Ok(output) if output.error.is_some() => Some(Failure::Call),
+                Ok(_) => None,
+                Err((failure, _)) => *failure,
+            };
+
+            match failure {
+                Some(failure) if self.retry.retries(attempt, failure) => {
+                    let delay = self.retry.delay(attempt);
+                    log::debug!(
+                        "retrying request '{}' in {:?} after {:?}",
+                        request.id,
+                        delay,
+                        failure
+                    );
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                _ => return result.map_err(|(_, err)| err),
+            }
+        }
+    }
+
+    /// send the request and wait for its output, unless the circuit of the request
+    /// object is open. A failed attempt carries the kind of the failure if the request
+    /// can be retried.
+    async fn attempt(
+        &self,
+        module: &str,
+        request: Request,
+    ) -> std::result::Result<Output, (Option<Failure>, Error)> {
+        let object = request.object.clone();
+        self.guard(module, &object, self.exchange(module, request))
+            .await
+    }
+
+    /// run call unless the circuit of object is open, and record its result in the circuit
+    async fn guard<F, T>(
+        &self,
+        module: &str,
+        object: &ObjectID,
+        call: F,
+    ) -> std::result::Result<T, (Option<Failure>, Error)>
+    where
+        F: Future<Output = std::result::Result<T, (Option<Failure>, Error)>>,
+    {
+        let circuits = match &self.circuits {
+            Some(circuits) => circuits,
+            None => return call.await,
+        };
+
+        let key = format!("{}.{}", module, object);
+        let admit = circuits.admit(&key, Instant::now().into_std());
+        let open = match admit {
+            Admit::Pass => false,
+            Admit::Check if self.live(module).await => false,
+            Admit::Check => {
+                circuits.trip(&key, Instant::now().into_std());
+                true
+            }
+            Admit::Reject => true,
+        };
+
+        if open {
+            return Err((None, Error::CircuitOpen(key)));
+        }
+
+        let result = call.await;
+        let failure = match &result {
+            Err((failure, _)) => *failure,
+            Ok(_) => None,
+        };
+        circuits.record(&key, failure, Instant::now().into_std());
+
+        result
+    }
+
+    /// true if a server of the module published its info recently. A module can't
+    /// be checked if redis fails, it's then assumed to be live.
+    async fn live(&self, module: &str) -> bool {
+        let mut con = match self.pool.get().await {
+            Ok(con) => con,
+            Err(_) => return true,
+        };
+
+        con.exists(introspect::key(module)).await.unwrap_or(true)
+    }
+
+    /// send the request and wait for its output
+    async fn exchange(
+        &self,
+        module: &str,
+        request: Request,
+    ) -> std::result::Result<Output, (Option<Failure>, Error)> {
+        let response = self.transmit(module, request).await?;
+        if let Some(err) = response.error {
+            return Err((None, Error::Protocol(err)));
+        }
+
+        let mut output = response.output;
+        chunked::join_output(&self.pool, &mut output)
+            .await
+            .map_err(|err| (None, err))?;
+
+        Ok(output)
+    }
+
+    /// send the request and wait for its (first) response
+    async fn transmit(
+        &self,
+        module: &str,
+        request: Request,
+    ) -> std::result::Result<Response, (Option<Failure>, Error)> {
+        let id = request.id.clone();
+        self.send(module, request)
+            .await
+            .map_err(|err| (Some(Failure::Connection), err))?;
+
+        self.response(&id).await.map_err(|err| match err {
+            Error::Timeout(_) => (Some(Failure::Timeout), err),
+            err => (Some(Failure::Connection), err),
+        })
+    }
+
+    /// make a request to a method that streams its results. The receiver ends after the
+    /// last item, or after the error if the call failed.
+    pub async fn request_items<S, T>(&self, module: S, request: Request) -> Result<Receiver<T>>
+    where
+        S: AsRef<str>,
+        T: DeserializeOwned,
+    {
+        let module = module.as_ref();
+        let id = request.id.clone();
+        let object = request.object.clone();
+        let (receiver, source) = Receiver::with_codec(request.codec());
+        // the circuit of the object only sees the request and its first response, the
+        // method can take any time to produce the next items
+        let first = self
+            .guard(module, &object, self.transmit(module, request))
+            .await
+            .map_err(|(_, err)| err)?;
+
+        let client = self.clone();
+        tokio::spawn(async move {
+            let mut response = Ok(first);
+            loop {
+                let item = match response {
+                    Err(err) => Err(err),
+                    Ok(response) => match (response.error, response.output.error) {
+                        (Some(err), _) => Err(Error::Protocol(err)),
+                        (None, Some(err)) => Err(Error::Call(err)),
+                        // end of the items
+                        (None, None) if !response.more => break,
+                        (None, None) => Ok(response.output.data),
+                    },
+                };
+
+                let failed = item.is_err();
+                if source.send(item).await.is_err() || failed {
+                    break;
+                }
+
+                response = client.response(&id).await;
+            }
+        });
+
+        Ok(receiver)
+    }
+
+    /// push the request to the module queue
+    async fn send<S>(&self, module: S, mut request: Request) -> Result<()>
     where
         S: AsRef<str>,
     {
@@ -85,27 +468,132 @@ impl Client {
                 Error::Protocol(format!("failed to get redis connection: {}", err))
             })?;
 
-        let queue = format!("{}.{}", module.as_ref(), request.object);
+        let queue = request.queue(module);
+        if let Some(threshold) = self.compression {
+            request.compress(threshold);
+        }
+
+        if let Some(size) = self.chunking {
+            chunked::split_request(&self.pool, &mut request, size).await?;
+        }
 
         con.rpush(queue, &request)
             .await
             .map_err(|err| Error::Protocol(format!("failed to send request: {}", err)))?;
 
-        // wait for response
-        // todo: timeout on response
-        let (_, response): (String, Response) = con
-            .blpop(&request.id, 0)
+        Ok(())
+    }
+
+    /// wait for the next response to the request with id, up to the client timeout
+    async fn response(&self, id: &str) -> Result<Response> {
+        let mut con =
+            self.pool.get().await.map_err(|err| {
+                Error::Protocol(format!("failed to get redis connection: {}", err))
+            })?;
+
+        // a zero timeout blocks forever
+        let timeout = self
+            .timeout
+            .map(|timeout| (timeout.as_secs_f64().ceil() as usize).max(1))
+            .unwrap_or(0);
+        let response: Option<(String, Response)> = con
+            .blpop(id, timeout)
             .await
             .map_err(|err| Error::Protocol(format!("failed to get response: {}", err)))?;
 
-        if let Some(err) = response.error {
-            return Err(Error::Protocol(err));
+        match response {
+            Some((_, response)) => Ok(response),
+            None => Err(Error::Timeout(id.into())),
         }
+    }
+
+    /// get information about a running module, its objects and the health of
+    /// their streams. fails if the module is not running.
+    pub async fn introspect<S>(&self, module: S) -> Result<ModuleInfo>
+    where
+        S: AsRef<str>,
+    {
+        let mut con =
+            self.pool.get().await.map_err(|err| {
+                Error::Protocol(format!("failed to get redis connection: {}", err))
+            })?;
 
-        Ok(response.output)
+        let data: Option<Vec<u8>> = con
+            .get(introspect::key(module.as_ref()))
+            .await
+            .map_err(|err| Error::Protocol(format!("failed to get module info: {}", err)))?;
+
+        match data {
+            Some(data) => Codec::MsgPack.decode(&data),
+            None => Err(Error::Protocol(format!(
+                "module '{}' is not running",
+                module.as_ref()
+            ))),
+        }
     }
 
-    fn subscribe<C: AsRef<str>>(info: ConnectionInfo, source: Source, ch: C) -> anyhow::Result<()> {
+    /// get the info of all live replicas of a module (see [`crate::Server::with_replica`])
+    pub async fn replicas<S>(&self, module: S) -> Result<Vec<ModuleInfo>>
+    where
+        S: AsRef<str>,
+    {
+        let mut con =
+            self.pool.get().await.map_err(|err| {
+                Error::Protocol(format!("failed to get redis connection: {}", err))
+            })?;
+
+        let replicas: Vec<String> = con
+            .smembers(introspect::replicas_key(module.as_ref()))
+            .await
+            .map_err(|err| Error::Protocol(format!("failed to list replicas: {}", err)))?;
+
+        let mut infos = vec![];
+        for replica in replicas {
+            let data: Option<Vec<u8>> = con
+                .get(introspect::replica_key(module.as_ref(), &replica))
+                .await
+                .map_err(|err| Error::Protocol(format!("failed to get module info: {}", err)))?;
+
+            // the replica is gone
+            if let Some(data) = data {
+                infos.push(Codec::MsgPack.decode(&data)?);
+            }
+        }
+
+        Ok(infos)
+    }
+
+    /// get the id of the leader replica of a module (see [`crate::Server::leader`]).
+    /// None if the module has no leader, for example if all replicas are gone
+    pub async fn leader<S>(&self, module: S) -> Result<Option<String>>
+    where
+        S: AsRef<str>,
+    {
+        let mut con =
+            self.pool.get().await.map_err(|err| {
+                Error::Protocol(format!("failed to get redis connection: {}", err))
+            })?;
+
+        con.get(introspect::leader_key(module.as_ref()))
+            .await
+            .map_err(|err| Error::Protocol(format!("failed to get module leader: {}", err)))
+    }
+
+    /// find the latest live version of the object with name that matches the version
+    /// requirement (for example "^1"). The versions are discovered from the module info.
+    pub async fn resolve<S, N, R>(&self, module: S, name: N, req: R) -> Result<ObjectID>
+    where
+        S: AsRef<str>,
+        N: AsRef<str>,
+        R: AsRef<str>,
+    {
+        let req = crate::protocol::parse_version_req(req.as_ref())?;
+        let info = self.introspect(module).await?;
+        info.resolve(name.as_ref(), &req)
+            .ok_or_else(|| Error::UnknownObject(format!("{}@{}", name.as_ref(), req)))
+    }
+
+    fn pubsub<C: AsRef<str>>(info: ConnectionInfo, source: Source, ch: C) -> anyhow::Result<()> {
         let client = bb8_redis::redis::Client::open(info)?;
         let mut con = client.get_connection()?;
         let mut pubsub = con.as_pubsub();
@@ -133,14 +621,37 @@ impl Client {
         K: AsRef<str>,
         T: DeserializeOwned,
     {
-        let (receiver, source) = Receiver::new();
         let channel = format!("{}.{}.{}", module.as_ref(), object, key.as_ref());
 
+        Ok(self.listen(channel))
+    }
+
+    /// subscribe to a parameterized stream. The request method is the stream name, and the
+    /// inputs are the stream parameters. The server starts the stream for this set of parameters
+    /// if it's not already running and stops it once all its subscribers are gone.
+    pub async fn subscribe<S, T>(&self, module: S, mut request: Request) -> Result<Receiver<T>>
+    where
+        S: AsRef<str>,
+        T: DeserializeOwned,
+    {
+        request.kind = Kind::Subscribe;
+        let output = self.request(module, request).await?;
+        let channel: String = Result::from(output)?;
+
+        Ok(self.listen(channel))
+    }
+
+    /// listen to events published on channel
+    fn listen<T>(&self, channel: String) -> Receiver<T>
+    where
+        T: DeserializeOwned,
+    {
+        let (receiver, source) = Receiver::new();
         let info = self.info.clone();
         // unfortunately the async redis pubsub is not stable and is causing issues
         // hence we instead, starting a blocking thread to subscribe.
         tokio::task::spawn_blocking(move || {
-            if let Err(err) = Self::subscribe(info, source, &channel) {
+            if let Err(err) = Self::pubsub(info, source, &channel) {
                 log::error!(
                     "subscription to events channel '{}' stopped: {}",
                     channel,
@@ -149,6 +660,267 @@ impl Client {
             }
         });
 
-        Ok(receiver)
+        receiver
+    }
+}
+
+/// Session of a bidirectional call. Items of type I are sent to the handler,
+/// while items of type O are received from it. Once the client is done sending,
+/// the session is finished to get the call result R.
+///
+/// Session is also a [`Stream`] of the received items.
+pub struct Session<I, O, R> {
+    pool: Pool<RedisConnectionManager>,
+    input: Pipe,
+    closed: bool,
+    rx: mpsc::Receiver<Result<ByteBuf>>,
+    call: JoinHandle<Result<Output>>,
+    p: PhantomData<fn(I) -> (O, R)>,
+}
+
+impl<I, O, R> Session<I, O, R>
+where
+    I: Serialize,
+    O: DeserializeOwned,
+    R: DeserializeOwned,
+{
+    /// send an item to the handler
+    pub async fn send(&self, item: &I) -> Result<()> {
+        if self.closed {
+            return Err(Error::Protocol("session input is closed".into()));
+        }
+
+        self.input
+            .send(&self.pool, crate::protocol::encode(item)?)
+            .await
+    }
+
+    /// receive the next item sent by the handler. returns None once the
+    /// handler is done sending.
+    pub async fn recv(&mut self) -> Option<Result<O>> {
+        self.rx.recv().await.map(pipe::decode)
+    }
+
+    /// tell the handler there are no more items. Items can still be received
+    /// after the input is closed.
+    pub async fn close(&mut self) {
+        if !self.closed {
+            self.closed = true;
+            self.input.close(&self.pool, Ok(())).await;
+        }
+    }
+
+    /// close the input (if not closed) and wait for the call result
+    pub async fn finish(mut self) -> Result<R> {
+        self.close().await;
+        let output = (&mut self.call)
+            .await
+            .map_err(|err| Error::Protocol(format!("session call failed: {}", err)))??;
+
+        output.into()
+    }
+}
+
+impl<I, O, R> Stream for Session<I, O, R>
+where
+    O: DeserializeOwned,
+{
+    type Item = Result<O>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx).map(|item| item.map(pipe::decode))
+    }
+}
+
+impl<I, O, R> Drop for Session<I, O, R> {
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+
+        // a session that is dropped without finishing still needs to
+        // release the handler that is waiting for input.
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let pool = self.pool.clone();
+            let input = self.input.clone();
+            runtime.spawn(async move { input.close(&pool, Ok(())).await });
+        }
+    }
+}
+
+/// merge multiple receivers of the same event type in one stream. The merged
+/// stream ends once all receivers end.
+pub fn merge<T, I>(receivers: I) -> impl Stream<Item = anyhow::Result<T>>
+where
+    T: DeserializeOwned,
+    I: IntoIterator<Item = Receiver<T>>,
+{
+    stream::select_all(receivers)
+}
+
+/// Typed combinators for streams of events (like [`Receiver`]). Unlike `StreamExt`
+/// they work on the decoded events and pass errors through.
+pub trait EventStreamExt<T>: Stream<Item = anyhow::Result<T>> + Unpin + Sized {
+    /// only keep the events that match the predicate
+    fn filter_events<F>(self, predicate: F) -> FilterEvents<Self, F>
+    where
+        F: FnMut(&T) -> bool,
+    {
+        FilterEvents {
+            inner: self,
+            predicate,
+        }
+    }
+
+    /// map the events with f
+    fn map_events<U, F>(self, f: F) -> MapEvents<Self, F>
+    where
+        F: FnMut(T) -> U,
+    {
+        MapEvents { inner: self, f }
+    }
+
+    /// yield an [`Elapsed`] error if no event is received within duration since
+    /// the last event (or the start). The stream continues after the error.
+    fn timeout(self, duration: Duration) -> Timeout<Self> {
+        Timeout {
+            inner: self,
+            duration,
+            deadline: Box::pin(tokio::time::sleep(duration)),
+        }
+    }
+}
+
+impl<S, T> EventStreamExt<T> for S where S: Stream<Item = anyhow::Result<T>> + Unpin {}
+
+/// Stream returned by [`EventStreamExt::filter_events`]
+pub struct FilterEvents<S, F> {
+    inner: S,
+    predicate: F,
+}
+
+// the predicate is never pinned
+impl<S: Unpin, F> Unpin for FilterEvents<S, F> {}
+
+impl<S, T, F> Stream for FilterEvents<S, F>
+where
+    S: Stream<Item = anyhow::Result<T>> + Unpin,
+    F: FnMut(&T) -> bool,
+{
+    type Item = anyhow::Result<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match this.inner.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(event))) if !(this.predicate)(&event) => continue,
+                poll => return poll,
+            }
+        }
+    }
+}
+
+/// Stream returned by [`EventStreamExt::map_events`]
+pub struct MapEvents<S, F> {
+    inner: S,
+    f: F,
+}
+
+// the function is never pinned
+impl<S: Unpin, F> Unpin for MapEvents<S, F> {}
+
+impl<S, T, U, F> Stream for MapEvents<S, F>
+where
+    S: Stream<Item = anyhow::Result<T>> + Unpin,
+    F: FnMut(T) -> U,
+{
+    type Item = anyhow::Result<U>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        this.inner
+            .poll_next_unpin(cx)
+            .map(|event| event.map(|event| event.map(&mut this.f)))
+    }
+}
+
+/// error returned by the [`Timeout`] stream
+#[derive(Error, Debug)]
+#[error("no event received within {0:?}")]
+pub struct Elapsed(pub Duration);
+
+/// Stream returned by [`EventStreamExt::timeout`]
+pub struct Timeout<S> {
+    inner: S,
+    duration: Duration,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl<S, T> Stream for Timeout<S>
+where
+    S: Stream<Item = anyhow::Result<T>> + Unpin,
+{
+    type Item = anyhow::Result<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if let Poll::Ready(event) = this.inner.poll_next_unpin(cx) {
+            this.deadline.as_mut().reset(Instant::now() + this.duration);
+            return Poll::Ready(event);
+        }
+
+        match this.deadline.as_mut().poll(cx) {
+            Poll::Ready(_) => {
+                this.deadline.as_mut().reset(Instant::now() + this.duration);
+                Poll::Ready(Some(Err(Elapsed(this.duration).into())))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn source(values: &[u32]) -> Receiver<u32> {
+        let (receiver, source) = Receiver::new();
+        let values = values.to_vec();
+        tokio::spawn(async move {
+            for value in values {
+                let msg = crate::protocol::encode(value).unwrap();
+                source.send(Ok(msg)).await.unwrap();
+            }
+        });
+
+        receiver
+    }
+
+    #[tokio::test]
+    async fn combinators() {
+        let events: Vec<u32> = source(&[1, 2, 3, 4])
+            .await
+            .filter_events(|v| v % 2 == 0)
+            .map_events(|v| v * 10)
+            .map(|v| v.unwrap())
+            .collect()
+            .await;
+        assert_eq!(events, vec![20, 40]);
+
+        let mut events: Vec<u32> = merge(vec![source(&[1, 2]).await, source(&[3]).await])
+            .map(|v| v.unwrap())
+            .collect()
+            .await;
+        events.sort_unstable();
+        assert_eq!(events, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let (receiver, _source) = Receiver::<u32>::new();
+        let mut receiver = receiver.timeout(Duration::from_millis(10));
+
+        let err = receiver.next().await.unwrap().unwrap_err();
+        assert!(err.downcast_ref::<Elapsed>().is_some());
     }
 }
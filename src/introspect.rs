@@ -0,0 +1,194 @@
+//! Module introspection.
+//!
+//! A running server periodically publishes a [`ModuleInfo`] under the
+//! `{module}.$info` key. It describes the registered objects and the health
+//! of their streams. The key expires if the server stops, so clients can use
+//! [`crate::Client::introspect`] to also check if a module is alive.
+//!
+//! If a module runs many replicas, each replica also publishes its own info under
+//! `{module}.$info.{replica}` and is listed in the `{module}.$replicas` set. Use
+//! [`crate::Client::replicas`] to get the info of all live replicas, and
+//! [`crate::Client::leader`] to get the id of the leader replica.
+use crate::protocol::{self, ObjectID};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// how often a server publishes its info
+pub const INFO_INTERVAL: Duration = Duration::from_secs(10);
+/// info key expires if not refreshed within this time (in seconds)
+pub(crate) const INFO_TTL: usize = 3 * INFO_INTERVAL.as_secs() as usize;
+
+/// redis key of the module info
+pub(crate) fn key<S: AsRef<str>>(module: S) -> String {
+    format!("{}.$info", module.as_ref())
+}
+
+/// redis key of the info of a module replica
+pub(crate) fn replica_key<S: AsRef<str>>(module: S, replica: &str) -> String {
+    format!("{}.$info.{}", module.as_ref(), replica)
+}
+
+/// redis key of the set of the module replicas
+pub(crate) fn replicas_key<S: AsRef<str>>(module: S) -> String {
+    format!("{}.$replicas", module.as_ref())
+}
+
+/// redis key of the leader lease of a module, it holds the id of the leader replica
+pub(crate) fn leader_key<S: AsRef<str>>(module: S) -> String {
+    format!("{}.$leader", module.as_ref())
+}
+
+/// Health of a stream producer
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Health {
+    /// the producer is running
+    #[default]
+    Running,
+    /// the producer exited and is waiting to be restarted
+    Restarting,
+    /// the producer returned and was not restarted
+    Stopped,
+    /// the producer panicked and was not restarted
+    Failed,
+}
+
+/// Status of a stream producer
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StreamStatus {
+    #[serde(rename = "Health")]
+    pub health: Health,
+    /// number of times the producer was restarted
+    #[serde(rename = "Restarts")]
+    pub restarts: u32,
+    /// number of events dropped because the stream buffer was full
+    #[serde(rename = "Dropped", default)]
+    pub dropped: u64,
+    /// the reason of the last producer exit
+    #[serde(rename = "Error", default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StreamInfo {
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "Status")]
+    pub status: StreamStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ObjectInfo {
+    #[serde(rename = "ID")]
+    pub id: ObjectID,
+    #[serde(rename = "Streams", default)]
+    pub streams: Vec<StreamInfo>,
+    /// other versions of the object that are served by this object
+    #[serde(rename = "Aliases", default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+}
+
+impl ObjectInfo {
+    /// all versions served by this object
+    pub fn versions(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.id.version.as_str()).chain(self.aliases.iter().map(String::as_str))
+    }
+}
+
+/// Information about a running module
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModuleInfo {
+    #[serde(rename = "Module")]
+    pub module: String,
+    #[serde(rename = "Objects", default)]
+    pub objects: Vec<ObjectInfo>,
+    /// id of the replica that published the info
+    #[serde(rename = "Replica", default, skip_serializing_if = "Option::is_none")]
+    pub replica: Option<String>,
+    /// unix timestamp (in seconds) of when the info was published
+    #[serde(rename = "Timestamp")]
+    pub timestamp: u64,
+}
+
+impl ModuleInfo {
+    pub(crate) fn new<S: Into<String>>(module: S, objects: Vec<ObjectInfo>) -> ModuleInfo {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        ModuleInfo {
+            module: module.into(),
+            objects,
+            replica: None,
+            timestamp,
+        }
+    }
+
+    /// find object by id
+    pub fn object(&self, id: &ObjectID) -> Option<&ObjectInfo> {
+        let id = id.to_string();
+        self.objects.iter().find(|o| o.id.to_string() == id)
+    }
+
+    /// all live versions of the object with name, from oldest to latest. Versions
+    /// that are not valid semver are listed first.
+    pub fn versions(&self, name: &str) -> Vec<String> {
+        let mut versions: Vec<String> = self
+            .objects
+            .iter()
+            .filter(|o| o.id.name == name)
+            .flat_map(|o| o.versions())
+            .map(String::from)
+            .collect();
+
+        versions.sort_by_key(|v| protocol::parse_version(v));
+        versions.dedup();
+        versions
+    }
+
+    /// the latest live version of the object with name that matches the version
+    /// requirement
+    pub fn resolve(&self, name: &str, req: &semver::VersionReq) -> Option<ObjectID> {
+        self.versions(name)
+            .into_iter()
+            .rev()
+            .find(|v| matches!(protocol::parse_version(v), Some(v) if req.matches(&v)))
+            .map(|version| ObjectID::new(name.into(), version))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn object(version: &str, aliases: &[&str]) -> ObjectInfo {
+        ObjectInfo {
+            id: ObjectID::new("calculator", version),
+            streams: vec![],
+            aliases: aliases.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn resolve() {
+        let info = ModuleInfo::new(
+            "test",
+            vec![object("2.0", &[]), object("1.2", &["1.0", "1.1"])],
+        );
+
+        assert_eq!(
+            info.versions("calculator"),
+            vec!["1.0", "1.1", "1.2", "2.0"]
+        );
+        assert!(info.versions("echo").is_empty());
+
+        let resolve = |req: &str| {
+            info.resolve("calculator", &protocol::parse_version_req(req).unwrap())
+                .map(|id| id.version)
+        };
+        assert_eq!(resolve("^1"), Some("1.2".into()));
+        assert_eq!(resolve("~1.1"), Some("1.1".into()));
+        assert_eq!(resolve("*"), Some("2.0".into()));
+        assert_eq!(resolve("^3"), None);
+    }
+}
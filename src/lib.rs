@@ -1,8 +1,14 @@
 use anyhow::Result;
 use bb8_redis::{bb8::Pool, RedisConnectionManager};
 
+mod breaker;
+pub mod chunked;
 pub mod client;
+pub mod introspect;
+pub mod pipe;
 pub mod protocol;
+pub mod recorder;
+mod retry;
 pub mod server;
 
 pub use client::Client;
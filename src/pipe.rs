@@ -0,0 +1,131 @@
+//! Pipes carry a sequence of items between a client and a call handler.
+//!
+//! A pipe is a side key (a redis list) bound to a request. Items are pushed to the
+//! key as frames, and an end frame is pushed once the writer is done. Each call has
+//! at most two pipes:
+//! - `{id}.in` carries the items sent by the client. The handler receives them with a
+//!   [`crate::server::Stream`] argument (client-streaming calls)
+//! - `{id}.out` carries the items sent by the handler with a [`crate::server::Sender`]
+//!   argument. The client receives them with a [`crate::client::Session`] (bidirectional calls)
+//!
+//! ```ignore
+//! #[object(name = "files", version = "1.0")]
+//! trait Files {
+//!     // client-streaming, the stub takes any stream of chunks
+//!     async fn store(&self, name: String, chunks: Stream<Chunk>) -> Result<u64>;
+//!     // bidirectional, the stub returns a Session<Line, Line, ()>
+//!     async fn shell(&self, input: Stream<Line>, output: Sender<Line>) -> Result<()>;
+//! }
+//! ```
+use crate::chunked::{self, Frame, MAX_PENDING};
+use crate::protocol::{self, Error, Request, Result};
+use bb8_redis::{bb8::Pool, redis::AsyncCommands, RedisConnectionManager};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use tokio::sync::mpsc;
+
+/// how long (in seconds) a reader waits for the next item
+const IDLE_TIMEOUT: usize = 5 * 60;
+
+/// Pipe is a handle to the side key of a call. It's what is sent over the wire
+/// in place of a Stream or Sender argument.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Pipe {
+    #[serde(rename = "Key")]
+    key: String,
+}
+
+impl Pipe {
+    /// pipe of the items sent by the client during the request
+    pub fn input(request: &Request) -> Pipe {
+        Pipe {
+            key: format!("{}.in", request.id),
+        }
+    }
+
+    /// pipe of the items sent by the handler during the request
+    pub fn output(request: &Request) -> Pipe {
+        Pipe {
+            key: format!("{}.out", request.id),
+        }
+    }
+
+    /// push an item to the pipe. waits if the reader is falling behind
+    pub(crate) async fn send(
+        &self,
+        pool: &Pool<RedisConnectionManager>,
+        item: ByteBuf,
+    ) -> Result<()> {
+        if chunked::push(pool, &self.key, &Frame::Data(item)).await? >= MAX_PENDING {
+            chunked::drain(pool, &self.key).await?;
+        }
+
+        Ok(())
+    }
+
+    /// tell the reader there are no more items. The reader gets the error
+    /// instead of the end of the items if the writer failed.
+    pub(crate) async fn close(&self, pool: &Pool<RedisConnectionManager>, result: Result<()>) {
+        let frame = match &result {
+            Ok(_) => Frame::End,
+            Err(err) => Frame::Error(err.to_string()),
+        };
+
+        if let Err(err) = result.and(chunked::push(pool, &self.key, &frame).await) {
+            log::error!("failed to write pipe '{}': {}", self.key, err);
+        }
+    }
+
+    /// start reading the items of the pipe. The returned receiver ends once
+    /// the writer closes the pipe.
+    pub(crate) fn reader(
+        &self,
+        pool: Pool<RedisConnectionManager>,
+    ) -> mpsc::Receiver<Result<ByteBuf>> {
+        let (tx, rx) = mpsc::channel(1);
+        let key = self.key.clone();
+        tokio::spawn(async move {
+            loop {
+                let item = match chunked::pull_timeout(&pool, &key, IDLE_TIMEOUT).await {
+                    Ok(Frame::Data(item)) => Ok(item),
+                    Ok(Frame::End) => break,
+                    Ok(Frame::Error(err)) => Err(Error::Protocol(err)),
+                    Err(err) => Err(err),
+                };
+
+                let failed = item.is_err();
+                if tx.send(item).await.is_err() || failed {
+                    break;
+                }
+            }
+
+            if let Ok(mut con) = pool.get().await {
+                let _ = con.del::<_, ()>(&key).await;
+            }
+        });
+
+        rx
+    }
+}
+
+/// decode an item received over a pipe
+pub(crate) fn decode<T>(item: Result<ByteBuf>) -> Result<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    protocol::Codec::MsgPack.decode(&item?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::ObjectID;
+
+    #[test]
+    fn keys() {
+        let request = Request::new(ObjectID::new("files", "1.0"), "store");
+        assert_eq!(Pipe::input(&request).key, format!("{}.in", request.id));
+        assert_eq!(Pipe::output(&request).key, format!("{}.out", request.id));
+        assert_ne!(Pipe::input(&request), Pipe::output(&request));
+    }
+}
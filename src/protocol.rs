@@ -1,12 +1,14 @@
 use bb8_redis::redis::{
     ErrorKind, FromRedisValue, RedisError, RedisResult, RedisWrite, ToRedisArgs, Value,
 };
-use rmp_serde::Serializer;
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use rmp_serde::Serializer as MsgPackSerializer;
+use serde::{de, de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
 use serde_bytes::ByteBuf;
+use std::borrow::Cow;
 use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::ops::Deref;
 
-#[derive(Debug, Serialize, Deserialize, thiserror::Error)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, thiserror::Error)]
 #[error("{message}")]
 pub struct CallError {
     #[serde(rename = "Message")]
@@ -21,12 +23,16 @@ impl CallError {
     }
 }
 
-#[derive(Debug, thiserror::Error)]
+#[derive(Debug, Clone, thiserror::Error)]
 pub enum Error {
     #[error("unknown object '{0}'")]
     UnknownObject(String),
     #[error("unknown method '{0}'")]
     UnknownMethod(String),
+    #[error("unknown stream '{0}'")]
+    UnknownStream(String),
+    #[error("object '{0}' is already registered")]
+    AlreadyRegistered(String),
     #[error("no argument found at index {0}")]
     ArgumentOutOfRange(usize),
     #[error("protocol error: {0}")]
@@ -35,6 +41,10 @@ pub enum Error {
     Encoding(String),
     #[error("remote call failed with error '{0}'")]
     Call(CallError),
+    #[error("no response to request '{0}' in time")]
+    Timeout(String),
+    #[error("circuit '{0}' is open, the object is not available")]
+    CircuitOpen(String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -46,6 +56,9 @@ pub struct ObjectID {
     pub name: String,
     #[serde(rename = "Version")]
     pub version: String,
+    /// name of the instance if a module serves multiple instances of the object
+    #[serde(rename = "Instance", default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
 }
 
 impl ObjectID {
@@ -53,8 +66,45 @@ impl ObjectID {
         ObjectID {
             name: name.into(),
             version: version.into(),
+            instance: None,
         }
     }
+
+    /// the id of the named instance of this object
+    pub fn with_instance<S: Into<String>>(mut self, instance: S) -> ObjectID {
+        self.instance = Some(instance.into());
+        self
+    }
+
+    /// version of the object as a semantic version. None if the version
+    /// is not a valid semver (see [`parse_version`])
+    pub fn semver(&self) -> Option<semver::Version> {
+        parse_version(&self.version)
+    }
+}
+
+/// parse an object version as a semantic version. Object versions usually omit
+/// the patch (or minor) number (for example "1.0"), missing numbers are set to 0.
+pub fn parse_version<S: AsRef<str>>(version: S) -> Option<semver::Version> {
+    let version = version.as_ref();
+    let (core, rest) = match version.find(['-', '+']) {
+        Some(index) => version.split_at(index),
+        None => (version, ""),
+    };
+
+    let missing = 3usize.saturating_sub(core.split('.').count());
+    semver::Version::parse(&format!("{}{}{}", core, ".0".repeat(missing), rest)).ok()
+}
+
+/// parse a version requirement (for example "^1" or ">=1.2, <2")
+pub fn parse_version_req<S: AsRef<str>>(req: S) -> Result<semver::VersionReq> {
+    semver::VersionReq::parse(req.as_ref()).map_err(|err| {
+        Error::Protocol(format!(
+            "invalid version requirement '{}': {}",
+            req.as_ref(),
+            err
+        ))
+    })
 }
 
 impl Display for ObjectID {
@@ -65,55 +115,537 @@ impl Display for ObjectID {
             write!(f, "{}@{}", self.name, self.version)?;
         }
 
+        if let Some(instance) = &self.instance {
+            write!(f, "/{}", instance)?;
+        }
+
         Ok(())
     }
 }
 
+/// Codec used to encode requests, responses and call arguments.
+///
+/// MessagePack is the default and the only codec understood by other
+/// zbus implementations. Json can be used by peers that can't speak
+/// MessagePack (shell, python scripts, etc..) and makes traffic human
+/// readable. The codec is negotiated per request, the server will
+/// always respond with the same codec used by the request.
+///
+/// Only calls use the negotiated codec: the request, its response and the
+/// items of streamed results. Stream events, pipe items (client-streaming
+/// calls and sessions) and the module info are always MessagePack, so a
+/// json peer can call methods but can't subscribe to streams or open sessions.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    MsgPack,
+    Json,
+}
+
+impl Codec {
+    /// detect the codec of an encoded envelope (request or response). Envelopes
+    /// are always maps, a msgpack map never starts with '{'
+    pub fn detect(data: &[u8]) -> Codec {
+        match data.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{') => Codec::Json,
+            _ => Codec::MsgPack,
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, o: T) -> Result<ByteBuf> {
+        match self {
+            Codec::MsgPack => {
+                let mut buffer: Vec<u8> = Vec::new();
+
+                let encoder = MsgPackSerializer::new(&mut buffer);
+                let mut encoder = encoder.with_struct_map();
+                o.serialize(&mut encoder)
+                    .map_err(|e| Error::Encoding(e.to_string()))?;
+
+                Ok(ByteBuf::from(buffer))
+            }
+            Codec::Json => serde_json::to_vec(&o)
+                .map(ByteBuf::from)
+                .map_err(|e| Error::Encoding(e.to_string())),
+        }
+    }
+
+    pub fn decode<'a, T>(&self, data: &'a [u8]) -> Result<T>
+    where
+        T: Deserialize<'a>,
+    {
+        match self {
+            Codec::MsgPack => {
+                rmp_serde::decode::from_read_ref(data).map_err(|e| Error::Encoding(e.to_string()))
+            }
+            Codec::Json => serde_json::from_slice(data).map_err(|e| Error::Encoding(e.to_string())),
+        }
+    }
+}
+
 pub fn encode<T: Serialize>(o: T) -> Result<ByteBuf> {
-    let mut buffer: Vec<u8> = Vec::new();
+    Codec::MsgPack.encode(o)
+}
+
+/// payload (de)serialization of encoded values. In human readable formats (json)
+/// payloads are written as strings (if possible) instead of a list of numbers.
+mod payload {
+    use serde::{Serialize, Serializer};
+    use serde_bytes::ByteBuf;
+
+    struct Payload<'a>(&'a [u8]);
+
+    impl<'a> Serialize for Payload<'a> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            match std::str::from_utf8(self.0) {
+                Ok(value) if serializer.is_human_readable() => serializer.serialize_str(value),
+                _ => serializer.serialize_bytes(self.0),
+            }
+        }
+    }
+
+    pub fn serialize<S: Serializer>(data: &ByteBuf, serializer: S) -> Result<S::Ok, S::Error> {
+        Payload(data).serialize(serializer)
+    }
+
+    pub fn serialize_all<S: Serializer>(
+        data: &[ByteBuf],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(data.iter().map(|d| Payload(d)))
+    }
+}
+
+/// transcoding of encoded values from one codec to another. Binary values become
+/// a list of numbers, which is how json encodes bytes.
+mod transcode {
+    use serde::de::{Deserialize, Deserializer, Error, MapAccess, SeqAccess, Visitor};
+    use serde_json::{Map, Number, Value};
+    use std::fmt::{Formatter, Result as FmtResult};
+
+    /// any decoded value
+    pub struct Any(pub Value);
+
+    impl<'de> Deserialize<'de> for Any {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_any(AnyVisitor).map(Any)
+        }
+    }
+
+    struct AnyVisitor;
+
+    impl<'de> Visitor<'de> for AnyVisitor {
+        type Value = Value;
+
+        fn expecting(&self, formatter: &mut Formatter) -> FmtResult {
+            formatter.write_str("any value")
+        }
+
+        fn visit_bool<E: Error>(self, v: bool) -> Result<Value, E> {
+            Ok(Value::Bool(v))
+        }
+
+        fn visit_i64<E: Error>(self, v: i64) -> Result<Value, E> {
+            Ok(v.into())
+        }
+
+        fn visit_u64<E: Error>(self, v: u64) -> Result<Value, E> {
+            Ok(v.into())
+        }
+
+        fn visit_f64<E: Error>(self, v: f64) -> Result<Value, E> {
+            Number::from_f64(v)
+                .map(Value::Number)
+                .ok_or_else(|| E::custom(format!("invalid number {}", v)))
+        }
+
+        fn visit_str<E: Error>(self, v: &str) -> Result<Value, E> {
+            Ok(Value::String(v.into()))
+        }
+
+        fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<Value, E> {
+            Ok(Value::Array(v.iter().map(|&b| b.into()).collect()))
+        }
+
+        fn visit_none<E: Error>(self) -> Result<Value, E> {
+            Ok(Value::Null)
+        }
+
+        fn visit_unit<E: Error>(self) -> Result<Value, E> {
+            Ok(Value::Null)
+        }
+
+        fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+            Any::deserialize(deserializer).map(|any| any.0)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+            let mut values = vec![];
+            while let Some(Any(value)) = seq.next_element()? {
+                values.push(value);
+            }
+
+            Ok(Value::Array(values))
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+            let mut values = Map::new();
+            while let Some((key, Any(value))) = map.next_entry::<String, Any>()? {
+                values.insert(key, value);
+            }
+
+            Ok(Value::Object(values))
+        }
+    }
+}
+
+/// BytesRef is a bytes argument that is decoded without copying when possible
+/// (when the encoded value is binary). It's used by the `object` macro to pass
+/// `&[u8]` arguments to methods, but can be used directly with [`Tuple::at`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct BytesRef<'a>(Cow<'a, [u8]>);
+
+impl<'a> BytesRef<'a> {
+    /// true if the bytes are borrowed from the encoded value
+    pub fn is_borrowed(&self) -> bool {
+        matches!(self.0, Cow::Borrowed(_))
+    }
+}
+
+impl<'a> From<&'a [u8]> for BytesRef<'a> {
+    fn from(data: &'a [u8]) -> Self {
+        BytesRef(Cow::Borrowed(data))
+    }
+}
+
+impl<'a> Deref for BytesRef<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'a> Serialize for BytesRef<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for BytesRef<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = Cow<'de, [u8]>;
+
+            fn expecting(&self, f: &mut Formatter) -> FmtResult {
+                write!(f, "bytes")
+            }
+
+            fn visit_borrowed_bytes<E: de::Error>(
+                self,
+                v: &'de [u8],
+            ) -> std::result::Result<Self::Value, E> {
+                Ok(Cow::Borrowed(v))
+            }
+
+            fn visit_borrowed_str<E: de::Error>(
+                self,
+                v: &'de str,
+            ) -> std::result::Result<Self::Value, E> {
+                Ok(Cow::Borrowed(v.as_bytes()))
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> std::result::Result<Self::Value, E> {
+                Ok(Cow::Owned(v.to_vec()))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<Self::Value, E> {
+                Ok(Cow::Owned(v.as_bytes().to_vec()))
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> std::result::Result<Self::Value, A::Error> {
+                let mut data = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+                while let Some(b) = seq.next_element()? {
+                    data.push(b);
+                }
+
+                Ok(Cow::Owned(data))
+            }
+        }
+
+        deserializer.deserialize_bytes(Visitor).map(BytesRef)
+    }
+}
+
+/// StrRef is a string argument that is decoded without copying when possible.
+/// It's used by the `object` macro to pass `&str` arguments to methods.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StrRef<'a>(Cow<'a, str>);
+
+impl<'a> StrRef<'a> {
+    /// true if the string is borrowed from the encoded value
+    pub fn is_borrowed(&self) -> bool {
+        matches!(self.0, Cow::Borrowed(_))
+    }
+}
+
+impl<'a> Deref for StrRef<'a> {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for StrRef<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = Cow<'de, str>;
+
+            fn expecting(&self, f: &mut Formatter) -> FmtResult {
+                write!(f, "string")
+            }
+
+            fn visit_borrowed_str<E: de::Error>(
+                self,
+                v: &'de str,
+            ) -> std::result::Result<Self::Value, E> {
+                Ok(Cow::Borrowed(v))
+            }
 
-    let encoder = Serializer::new(&mut buffer);
-    let mut encoder = encoder.with_struct_map();
-    o.serialize(&mut encoder)
-        .map_err(|e| Error::Encoding(e.to_string()))?;
+            fn visit_str<E: de::Error>(self, v: &str) -> std::result::Result<Self::Value, E> {
+                Ok(Cow::Owned(v.to_owned()))
+            }
 
-    Ok(ByteBuf::from(buffer))
+            fn visit_string<E: de::Error>(self, v: String) -> std::result::Result<Self::Value, E> {
+                Ok(Cow::Owned(v))
+            }
+        }
+
+        deserializer.deserialize_str(Visitor).map(StrRef)
+    }
+}
+
+/// compress data if it's larger than threshold. returns None if data
+/// is not compressed (small data, or compression doesn't make it smaller)
+fn compress(data: &[u8], threshold: usize) -> Option<ByteBuf> {
+    if data.len() <= threshold {
+        return None;
+    }
+
+    let compressed = lz4_flex::compress_prepend_size(data);
+    if compressed.len() >= data.len() {
+        return None;
+    }
+
+    Some(ByteBuf::from(compressed))
+}
+
+fn decompress(data: &[u8]) -> Result<ByteBuf> {
+    lz4_flex::decompress_size_prepended(data)
+        .map(ByteBuf::from)
+        .map_err(|e| Error::Encoding(format!("failed to decompress payload: {}", e)))
+}
+
+fn is_false(v: &bool) -> bool {
+    !v
+}
+
+/// Compression settings of a request. It's only set if the client
+/// enabled compression, hence other zbus peers never see it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Compression {
+    /// payloads larger than threshold (in bytes) are compressed. The same
+    /// threshold is used by the server to compress the call output.
+    #[serde(rename = "Threshold")]
+    pub threshold: usize,
+    /// indexes of the compressed inputs
+    #[serde(rename = "Inputs", default, skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<usize>,
+}
+
+/// Chunking settings of a request. It's only set if the client enabled
+/// chunked transfer, hence other zbus peers never see it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Chunking {
+    /// payloads larger than size (in bytes) are split in chunks of this size and
+    /// stored in side keys. The same size is used by the server to split the call output.
+    #[serde(rename = "Size")]
+    pub size: usize,
+    /// indexes of the chunked inputs
+    #[serde(rename = "Inputs", default, skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<usize>,
+}
+
+/// Kind of a request. Plain method calls are not flagged, hence other
+/// zbus peers never see it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Kind {
+    /// call of an object method
+    #[default]
+    Call,
+    /// subscription to a parameterized stream, the method is the stream name
+    /// and the inputs are the stream parameters. The output is the name of the
+    /// channel where the stream events are published.
+    Subscribe,
+}
+
+impl Kind {
+    fn is_call(&self) -> bool {
+        *self == Kind::Call
+    }
+}
+
+/// Priority of a call. Each priority has its own queue per object, and the server
+/// pulls all high priority calls (of all objects) before normal ones, and normal
+/// calls before low ones.
+///
+/// Normal calls use the standard zbus queue, other priorities are only served
+/// by rbus servers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Priority {
+    /// control calls (for example stop or abort) that shouldn't wait behind other calls
+    High,
+    #[default]
+    Normal,
+    /// bulk calls that can wait
+    Low,
+}
+
+impl Priority {
+    /// all priorities, from the highest
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    fn is_normal(&self) -> bool {
+        *self == Priority::Normal
+    }
+
+    /// the queue of the calls to object with this priority
+    pub fn queue<M, O>(&self, module: M, object: O) -> String
+    where
+        M: Display,
+        O: Display,
+    {
+        match self {
+            Priority::High => format!("{}.{}:high", module, object),
+            Priority::Normal => format!("{}.{}", module, object),
+            Priority::Low => format!("{}.{}:low", module, object),
+        }
+    }
 }
 
 /// Tuple is a list of arguments
-#[derive(Default, Serialize, Deserialize)]
+#[derive(Default, Clone, Serialize, Deserialize)]
 #[serde(transparent)]
-pub struct Tuple(Vec<serde_bytes::ByteBuf>);
+pub struct Tuple {
+    #[serde(serialize_with = "payload::serialize_all")]
+    values: Vec<ByteBuf>,
+    #[serde(skip)]
+    codec: Codec,
+}
 
 impl Tuple {
+    /// create an empty tuple where values are encoded with codec
+    pub fn new(codec: Codec) -> Tuple {
+        Tuple {
+            values: Vec::default(),
+            codec,
+        }
+    }
+
+    /// codec used to encode the tuple values
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub(crate) fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    /// compress all values larger than threshold. returns the indexes
+    /// of the compressed values
+    fn compress(&mut self, threshold: usize) -> Vec<usize> {
+        let mut compressed = vec![];
+        for (i, value) in self.values.iter_mut().enumerate() {
+            if let Some(data) = compress(value, threshold) {
+                *value = data;
+                compressed.push(i);
+            }
+        }
+
+        compressed
+    }
+
+    pub(crate) fn values_mut(&mut self) -> &mut Vec<ByteBuf> {
+        &mut self.values
+    }
+
+    /// decompress the values at the given indexes
+    fn decompress(&mut self, indexes: &[usize]) -> Result<()> {
+        for &i in indexes {
+            let value = self.values.get_mut(i).ok_or(Error::ArgumentOutOfRange(i))?;
+            *value = decompress(value)?;
+        }
+
+        Ok(())
+    }
+
     pub fn at<'a, T>(&'a self, i: usize) -> Result<T>
     where
         T: Deserialize<'a>,
     {
-        if i >= self.0.len() {
+        if i >= self.values.len() {
             return Err(Error::ArgumentOutOfRange(i));
         }
 
-        rmp_serde::decode::from_read_ref(&self.0[i]).map_err(|e| Error::Encoding(e.to_string()))
+        self.codec.decode(&self.values[i])
     }
 
     pub fn add<T>(&mut self, o: T) -> Result<()>
     where
         T: Serialize,
     {
-        self.0.push(encode(o)?);
+        self.values.push(self.codec.encode(o)?);
         Ok(())
     }
 }
 
+impl Tuple {
+    /// stable hash (FNV-1a) of the values. The values are decoded and encoded
+    /// again as msgpack so the same arguments always give the same fingerprint
+    /// whatever the codec and the encoder used by the caller.
+    pub(crate) fn fingerprint(&self) -> Result<u64> {
+        const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x100000001b3;
+
+        let mut hash = FNV_OFFSET;
+        for value in &self.values {
+            let transcode::Any(value) = self.codec.decode(value)?;
+            for byte in Codec::MsgPack.encode(&value)?.iter() {
+                hash = (hash ^ *byte as u64).wrapping_mul(FNV_PRIME);
+            }
+        }
+
+        Ok(hash)
+    }
+}
+
 impl Debug for Tuple {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        write!(f, "Arguments(len: {})", self.0.len())
+        write!(f, "Arguments(len: {})", self.values.len())
     }
 }
 
 /// rbus request
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Request {
     #[serde(rename = "ID")]
     pub id: String,
@@ -125,22 +657,108 @@ pub struct Request {
     pub reply_to: String,
     #[serde(rename = "Method")]
     pub method: String,
+    #[serde(
+        rename = "Compression",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub compression: Option<Compression>,
+    #[serde(rename = "Chunking", default, skip_serializing_if = "Option::is_none")]
+    pub chunking: Option<Chunking>,
+    #[serde(rename = "Kind", default, skip_serializing_if = "Kind::is_call")]
+    pub kind: Kind,
+    #[serde(
+        rename = "Priority",
+        default,
+        skip_serializing_if = "Priority::is_normal"
+    )]
+    pub priority: Priority,
+    /// set on requests that can be retried. The server runs the request once per
+    /// key and answers the retries with the same response.
+    #[serde(
+        rename = "IdempotencyKey",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub idempotency_key: Option<String>,
 }
 
 impl Request {
     /// create a new request on specific object id, and method name
     pub fn new<S: Into<String>>(object: ObjectID, method: S) -> Request {
+        Self::with_codec(object, method, Codec::default())
+    }
+
+    /// create a new request that is encoded with the given codec. The
+    /// response to this request will be encoded with the same codec.
+    pub fn with_codec<S: Into<String>>(object: ObjectID, method: S, codec: Codec) -> Request {
         let id = uuid::Uuid::new_v4().to_string();
         // generate a new ID
         Request {
             object,
             id: id.clone(),
             method: method.into(),
-            inputs: Tuple::default(),
+            inputs: Tuple::new(codec),
             reply_to: id,
+            compression: None,
+            chunking: None,
+            kind: Kind::Call,
+            priority: Priority::Normal,
+            idempotency_key: None,
         }
     }
 
+    /// set the priority of the request
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// give the request a new idempotency key so the client can retry it (see
+    /// [`crate::client::Retry`]). The retries of the request carry the same key and id.
+    pub fn idempotent(mut self) -> Self {
+        self.idempotency_key = Some(uuid::Uuid::new_v4().to_string());
+        self
+    }
+
+    /// the queue of the request on module
+    pub fn queue<S: AsRef<str>>(&self, module: S) -> String {
+        self.priority.queue(module.as_ref(), &self.object)
+    }
+
+    /// compress all inputs larger than threshold (in bytes). It also
+    /// tells the server to compress the output if it's larger than threshold
+    pub fn compress(&mut self, threshold: usize) {
+        let inputs = self.inputs.compress(threshold);
+        self.compression = Some(Compression { threshold, inputs });
+    }
+
+    /// decompress compressed inputs. Inputs that are still chunked are
+    /// skipped, they are decompressed once they are joined.
+    pub(crate) fn decompress(&mut self) -> Result<()> {
+        let compression = match self.compression.as_mut() {
+            Some(compression) => compression,
+            None => return Ok(()),
+        };
+
+        let chunked = self
+            .chunking
+            .as_ref()
+            .map(|c| c.inputs.as_slice())
+            .unwrap_or_default();
+
+        let (pending, ready): (Vec<usize>, Vec<usize>) =
+            compression.inputs.iter().partition(|i| chunked.contains(i));
+
+        compression.inputs = pending;
+        self.inputs.decompress(&ready)
+    }
+
+    /// codec used by this request
+    pub fn codec(&self) -> Codec {
+        self.inputs.codec()
+    }
+
     /// add an call argument to the request. The number and types
     /// of arguments added must match the expected type in server implementation
     pub fn arg<T>(mut self, argument: T) -> Result<Self>
@@ -164,13 +782,25 @@ impl FromRedisValue for Request {
             }
         };
 
-        rmp_serde::decode::from_read_ref(bytes).map_err(|err| {
+        let codec = Codec::detect(bytes);
+        let mut request: Request = codec.decode(bytes).map_err(|err| {
             RedisError::from((
                 ErrorKind::TypeError,
                 "failed to decode request",
                 err.to_string(),
             ))
-        })
+        })?;
+
+        request.inputs.set_codec(codec);
+        request.decompress().map_err(|err| {
+            RedisError::from((
+                ErrorKind::TypeError,
+                "failed to decode request",
+                err.to_string(),
+            ))
+        })?;
+
+        Ok(request)
     }
 }
 
@@ -179,24 +809,106 @@ impl ToRedisArgs for Request {
     where
         W: ?Sized + RedisWrite,
     {
-        let mut buffer: Vec<u8> = Vec::new();
-
-        let encoder = Serializer::new(&mut buffer);
-        let mut encoder = encoder.with_struct_map();
-        self.serialize(&mut encoder)
-            .expect("failed to encode response");
+        let buffer = self.codec().encode(self).expect("failed to encode request");
 
         out.write_arg(&buffer);
     }
 }
 
 /// Output from a call
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Output {
-    #[serde(rename = "Data")]
+    #[serde(rename = "Data", serialize_with = "payload::serialize")]
     pub data: serde_bytes::ByteBuf,
     #[serde(rename = "Error")]
     pub error: Option<CallError>,
+    #[serde(rename = "Compressed", default, skip_serializing_if = "is_false")]
+    pub compressed: bool,
+    #[serde(rename = "Chunked", default, skip_serializing_if = "is_false")]
+    pub chunked: bool,
+    #[serde(skip)]
+    codec: Codec,
+}
+
+impl Output {
+    /// create an output from a call result where data is encoded with codec
+    pub fn with_codec<T, E>(codec: Codec, res: std::result::Result<T, E>) -> Self
+    where
+        T: Serialize,
+        E: Display,
+    {
+        let (data, error) = match res.map_err(CallError::from) {
+            Ok(t) => match codec.encode(t) {
+                Ok(data) => (data, None),
+                Err(err) => (ByteBuf::default(), Some(CallError::from(err))),
+            },
+            Err(err) => (ByteBuf::default(), Some(err)),
+        };
+
+        Self {
+            data,
+            error,
+            codec,
+            compressed: false,
+            chunked: false,
+        }
+    }
+
+    /// compress output data if it's larger than threshold (in bytes)
+    pub fn compress(&mut self, threshold: usize) {
+        if self.compressed || self.chunked {
+            return;
+        }
+
+        if let Some(data) = compress(&self.data, threshold) {
+            self.data = data;
+            self.compressed = true;
+        }
+    }
+
+    /// decompress output data if it's compressed and not chunked
+    pub(crate) fn decompress(&mut self) -> Result<()> {
+        if !self.compressed || self.chunked {
+            return Ok(());
+        }
+
+        self.data = decompress(&self.data)?;
+        self.compressed = false;
+        Ok(())
+    }
+
+    /// codec used to encode the output data
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// re-encode the output data with codec. Outputs built by hand (with
+    /// [`Output::from`]) use the default codec whatever the request codec is.
+    pub(crate) fn transcode(&mut self, codec: Codec) -> Result<()> {
+        if self.codec == codec {
+            return Ok(());
+        }
+
+        if !self.data.is_empty() {
+            if self.compressed || self.chunked {
+                return Err(Error::Encoding(
+                    "can't transcode compressed or chunked output".into(),
+                ));
+            }
+
+            let transcode::Any(value) = self.codec.decode(&self.data)?;
+            self.data = codec.encode(value)?;
+        }
+
+        self.codec = codec;
+        Ok(())
+    }
+}
+
+impl PartialEq for Output {
+    fn eq(&self, other: &Self) -> bool {
+        self.data == other.data && self.error == other.error
+    }
 }
 
 impl<T, E> From<std::result::Result<T, E>> for Output
@@ -205,12 +917,7 @@ where
     E: Display,
 {
     fn from(res: std::result::Result<T, E>) -> Self {
-        let (data, error) = match res {
-            Ok(t) => (encode(t).unwrap(), None),
-            Err(err) => (ByteBuf::default(), Some(CallError::from(err))),
-        };
-
-        Self { data, error }
+        Output::with_codec(Codec::default(), res)
     }
 }
 
@@ -224,12 +931,12 @@ where
         }
 
         log::debug!("load type {}", std::any::type_name::<T>());
-        rmp_serde::decode::from_read_ref(&out.data).map_err(|e| Error::Encoding(e.to_string()))
+        out.codec.decode(&out.data)
     }
 }
 
 /// Response returned from a request
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Response {
     #[serde(rename = "ID")]
     pub id: String,
@@ -237,6 +944,49 @@ pub struct Response {
     pub output: Output,
     #[serde(rename = "Error")]
     pub error: Option<String>,
+    /// set on the responses that carry the items of a call that streams its
+    /// results. The last response (without More) ends the items.
+    #[serde(rename = "More", default, skip_serializing_if = "is_false")]
+    pub more: bool,
+}
+
+impl Response {
+    /// build a response to request with given id from the dispatch result. The
+    /// response is encoded with the given codec (usually the request codec)
+    pub fn new<S: Into<String>>(id: S, codec: Codec, ret: Result<Output>) -> Response {
+        let id = id.into();
+        let ret = ret.and_then(|mut output| output.transcode(codec).map(|_| output));
+        match ret {
+            Ok(output) => Response {
+                id,
+                output,
+                error: None,
+                more: false,
+            },
+            Err(err) => Response {
+                id,
+                output: Output {
+                    codec,
+                    ..Default::default()
+                },
+                error: Some(err.to_string()),
+                more: false,
+            },
+        }
+    }
+
+    /// build a response that carries one item of a call that streams its results
+    pub fn item<S: Into<String>>(id: S, output: Output) -> Response {
+        Response {
+            more: true,
+            ..Response::new(id, output.codec, Ok(output))
+        }
+    }
+
+    /// codec used to encode this response
+    pub fn codec(&self) -> Codec {
+        self.output.codec
+    }
 }
 
 impl FromRedisValue for Response {
@@ -251,13 +1001,25 @@ impl FromRedisValue for Response {
             }
         };
 
-        rmp_serde::decode::from_read_ref(bytes).map_err(|err| {
+        let codec = Codec::detect(bytes);
+        let mut response: Response = codec.decode(bytes).map_err(|err| {
             RedisError::from((
                 ErrorKind::TypeError,
-                "failed to decode request",
+                "failed to decode response",
                 err.to_string(),
             ))
-        })
+        })?;
+
+        response.output.codec = codec;
+        response.output.decompress().map_err(|err| {
+            RedisError::from((
+                ErrorKind::TypeError,
+                "failed to decode response",
+                err.to_string(),
+            ))
+        })?;
+
+        Ok(response)
     }
 }
 
@@ -266,11 +1028,9 @@ impl ToRedisArgs for Response {
     where
         W: ?Sized + RedisWrite,
     {
-        let mut buffer: Vec<u8> = Vec::new();
-
-        let encoder = Serializer::new(&mut buffer);
-        let mut encoder = encoder.with_struct_map();
-        self.serialize(&mut encoder)
+        let buffer = self
+            .codec()
+            .encode(self)
             .expect("failed to encode response");
 
         out.write_arg(&buffer);
@@ -281,6 +1041,61 @@ impl ToRedisArgs for Response {
 mod test {
     use super::*;
 
+    #[test]
+    fn object_id() {
+        let id = ObjectID::new("disk", "1.0");
+        assert_eq!(id.to_string(), "disk@1.0");
+        assert_eq!(id.with_instance("sda").to_string(), "disk@1.0/sda");
+        assert_eq!(ObjectID::new("disk", "").to_string(), "disk");
+    }
+
+    #[test]
+    fn priority() {
+        let request = Request::new(ObjectID::new("disk", "1.0"), "stop");
+        assert_eq!(request.queue("storage"), "storage.disk@1.0");
+
+        let request = request.with_priority(Priority::High);
+        assert_eq!(request.queue("storage"), "storage.disk@1.0:high");
+
+        // the priority survives the wire, and normal requests don't carry it
+        let encoded = encode(&request).unwrap();
+        let decoded: Request = rmp_serde::from_read_ref(&encoded).unwrap();
+        assert_eq!(decoded.priority, Priority::High);
+        let normal = Request::new(ObjectID::new("disk", "1.0"), "read");
+        let encoded = serde_json::to_string(&normal).unwrap();
+        assert!(!encoded.contains("Priority"));
+    }
+
+    #[test]
+    fn idempotent() {
+        let request = Request::new(ObjectID::new("disk", "1.0"), "write");
+        let encoded = serde_json::to_string(&request).unwrap();
+        assert!(!encoded.contains("IdempotencyKey"));
+
+        // the retries of a request carry the same key
+        let request = request.idempotent();
+        let retry = request.clone();
+        let encoded = encode(&retry).unwrap();
+        let decoded: Request = rmp_serde::from_read_ref(&encoded).unwrap();
+        assert!(decoded.idempotency_key.is_some());
+        assert_eq!(decoded.idempotency_key, request.idempotency_key);
+    }
+
+    #[test]
+    fn versions() {
+        let version = |v: &str| parse_version(v).map(|v| v.to_string());
+        assert_eq!(version("1"), Some("1.0.0".into()));
+        assert_eq!(version("1.2"), Some("1.2.0".into()));
+        assert_eq!(version("1.2.3"), Some("1.2.3".into()));
+        assert_eq!(version("1.2-beta"), Some("1.2.0-beta".into()));
+        assert_eq!(version("one"), None);
+
+        let req = parse_version_req("^1").unwrap();
+        assert!(req.matches(&ObjectID::new("calculator", "1.1").semver().unwrap()));
+        assert!(!req.matches(&ObjectID::new("calculator", "2.0").semver().unwrap()));
+        assert!(parse_version_req("not a version").is_err());
+    }
+
     #[test]
     fn tuple() {
         let mut tuple = Tuple::default();
@@ -306,4 +1121,160 @@ mod test {
             Some(err) if err.message == "some call test"
         ));
     }
+
+    #[test]
+    fn json() {
+        let request = Request::with_codec(ObjectID::new("calculator", "1.0"), "add", Codec::Json)
+            .arg(10)
+            .unwrap()
+            .arg("hello")
+            .unwrap();
+
+        let encoded = request.to_redis_args().remove(0);
+        let text = std::str::from_utf8(&encoded).unwrap();
+        assert!(text.contains(r#""Inputs":["10","\"hello\""]"#));
+
+        let request = Request::from_redis_value(&Value::Data(encoded)).unwrap();
+        assert_eq!(request.codec(), Codec::Json);
+        assert_eq!(request.inputs.at::<i32>(0).unwrap(), 10);
+        assert_eq!(request.inputs.at::<String>(1).unwrap(), "hello");
+
+        let output = Output::with_codec(request.codec(), Result::Ok(20));
+        let response = Response::new(&request.id, request.codec(), Ok(output));
+        let encoded = response.to_redis_args().remove(0);
+        assert!(encoded.starts_with(b"{"));
+
+        let response = Response::from_redis_value(&Value::Data(encoded)).unwrap();
+        assert_eq!(response.codec(), Codec::Json);
+        let result: Result<i32> = response.output.into();
+        assert_eq!(result.unwrap(), 20);
+    }
+
+    #[test]
+    fn fingerprint() {
+        let id = ObjectID::new("disk", "1.0");
+        let msgpack = Request::new(id.clone(), "events")
+            .arg(10)
+            .unwrap()
+            .arg(std::collections::HashMap::from([("b", 2), ("a", 1)]))
+            .unwrap();
+        let json = Request::with_codec(id, "events", Codec::Json)
+            .arg(10u8)
+            .unwrap()
+            .arg(std::collections::HashMap::from([("a", 1), ("b", 2)]))
+            .unwrap();
+
+        let fingerprint = msgpack.inputs.fingerprint().unwrap();
+        assert_eq!(fingerprint, json.inputs.fingerprint().unwrap());
+        // the fingerprint is part of stream channel names, it must never change
+        assert_eq!(fingerprint, 0x68c324ce9bf45841);
+    }
+
+    #[test]
+    fn compression() {
+        let data = vec![7u8; 4096];
+        let mut request = Request::new(ObjectID::new("calculator", "1.0"), "add")
+            .arg(10)
+            .unwrap()
+            .arg(serde_bytes::Bytes::new(&data))
+            .unwrap();
+        request.compress(1024);
+        assert!(matches!(&request.compression, Some(c) if c.inputs == vec![1]));
+
+        let encoded = request.to_redis_args().remove(0);
+        assert!(encoded.len() < data.len());
+
+        let request = Request::from_redis_value(&Value::Data(encoded)).unwrap();
+        assert_eq!(request.inputs.at::<i32>(0).unwrap(), 10);
+        assert_eq!(
+            request.inputs.at::<ByteBuf>(1).unwrap().as_slice(),
+            &data[..]
+        );
+
+        let mut output = Output::from(Result::Ok(ByteBuf::from(data.clone())));
+        output.compress(request.compression.unwrap().threshold);
+        assert!(output.compressed);
+
+        let response = Response::new(&request.id, request.inputs.codec(), Ok(output));
+        let encoded = response.to_redis_args().remove(0);
+        let response = Response::from_redis_value(&Value::Data(encoded)).unwrap();
+        assert!(!response.output.compressed);
+        let result: Result<ByteBuf> = response.output.into();
+        assert_eq!(result.unwrap().as_slice(), &data[..]);
+    }
+
+    #[test]
+    fn borrowed() {
+        let data = [1u8, 2, 3];
+        for codec in [Codec::MsgPack, Codec::Json] {
+            let mut tuple = Tuple::new(codec);
+            tuple.add(BytesRef::from(&data[..])).unwrap();
+            tuple.add("hello").unwrap();
+
+            let bytes: BytesRef = tuple.at(0).unwrap();
+            assert_eq!(&*bytes, &data[..]);
+            let str: StrRef = tuple.at(1).unwrap();
+            assert_eq!(&*str, "hello");
+            assert!(str.is_borrowed());
+
+            // only msgpack has a binary type that can be borrowed
+            assert_eq!(bytes.is_borrowed(), codec == Codec::MsgPack);
+        }
+    }
+
+    #[test]
+    fn detect() {
+        let request = Request::new(ObjectID::new("calculator", "1.0"), "add");
+        let encoded = request.to_redis_args().remove(0);
+        assert_eq!(Codec::detect(&encoded), Codec::MsgPack);
+        assert_eq!(Codec::detect(b" {}"), Codec::Json);
+    }
+
+    // an object that builds its outputs by hand (with the default codec)
+    struct Calculator;
+
+    #[async_trait::async_trait]
+    impl crate::server::Object for Calculator {
+        fn id(&self) -> ObjectID {
+            ObjectID::new("calculator", "1.0")
+        }
+
+        fn streams(&self) -> Result<std::collections::HashMap<String, crate::server::Sink>> {
+            Ok(Default::default())
+        }
+
+        async fn dispatch(&self, request: Request) -> Result<Output> {
+            let a: i32 = request.inputs.at(0)?;
+            let b: i32 = request.inputs.at(1)?;
+            let bytes = ByteBuf::from(vec![1, 2, 3]);
+            Ok(Result::<(i32, ByteBuf)>::Ok((a + b, bytes)).into())
+        }
+    }
+
+    #[tokio::test]
+    async fn json_object() {
+        use crate::server::Object;
+
+        let request = Request::with_codec(Calculator.id(), "add", Codec::Json)
+            .arg(10)
+            .unwrap()
+            .arg(20)
+            .unwrap();
+
+        let output = Calculator.dispatch(request.clone()).await.unwrap();
+        assert_eq!(output.codec(), Codec::MsgPack);
+
+        let response = Response::new(&request.id, request.codec(), Ok(output));
+        assert_eq!(response.codec(), Codec::Json);
+        let encoded = response.to_redis_args().remove(0);
+        let text = std::str::from_utf8(&encoded).unwrap();
+        assert!(text.contains(r#""Data":"[30,[1,2,3]]""#));
+
+        let response = Response::from_redis_value(&Value::Data(encoded)).unwrap();
+        assert_eq!(response.codec(), Codec::Json);
+        let result: Result<(i32, ByteBuf)> = response.output.into();
+        let (sum, bytes) = result.unwrap();
+        assert_eq!(sum, 30);
+        assert_eq!(bytes.as_slice(), &[1, 2, 3]);
+    }
 }
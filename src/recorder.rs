@@ -0,0 +1,318 @@
+//! Traffic recording and replaying.
+//!
+//! A [`Recorder`] wraps registered objects on the server side and appends every
+//! [`Request`] and the [`Response`] it produced to a file (one json record per line).
+//! The [`Replayer`] can later re-issue the recorded requests against another server
+//! and report the calls where the outputs differ. This is useful to regression-test
+//! a new object version against real traffic.
+use crate::client::Client;
+use crate::protocol::{Codec, Error, ObjectID, Output, Request, Response, Result, Tuple};
+use crate::server::{Concurrency, Object, Sink};
+use anyhow::Context;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+
+/// A single recorded call
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Record {
+    /// unix timestamp (in milliseconds) of when the request was received
+    pub timestamp: u128,
+    /// codec used by the request
+    #[serde(default)]
+    pub codec: Codec,
+    pub request: Request,
+    pub response: Response,
+}
+
+impl Record {
+    fn new(received: SystemTime, request: Request, response: Response) -> Record {
+        let timestamp = received
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+
+        Record {
+            timestamp,
+            codec: request.codec(),
+            request,
+            response,
+        }
+    }
+}
+
+/// Recorder appends recorded calls to a file. The recorder can be cloned
+/// and used to wrap multiple objects, all records go to the same file.
+#[derive(Clone)]
+pub struct Recorder {
+    file: Arc<Mutex<File>>,
+}
+
+impl Recorder {
+    /// create a new recorder that appends to the file at path. The file
+    /// is created if it does not exist.
+    pub async fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Recorder> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.as_ref())
+            .await
+            .with_context(|| format!("failed to open '{}'", path.as_ref().display()))?;
+
+        Ok(Recorder {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// wrap an object so all calls dispatched to it are recorded. The returned
+    /// object can be registered on the server instead of the original one.
+    pub fn wrap<O>(&self, object: O) -> Recorded<O>
+    where
+        O: Object + Send + Sync + 'static,
+    {
+        Recorded {
+            inner: object,
+            recorder: self.clone(),
+        }
+    }
+
+    async fn record(&self, record: &Record) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let mut file = self.file.lock().await;
+        file.write_all(&line).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+/// Recorded is an object wrapper that records all requests and responses
+/// of the inner object. Created with [`Recorder::wrap`]
+pub struct Recorded<O> {
+    inner: O,
+    recorder: Recorder,
+}
+
+#[async_trait]
+impl<O> Object for Recorded<O>
+where
+    O: Object + Send + Sync + 'static,
+{
+    fn id(&self) -> ObjectID {
+        self.inner.id()
+    }
+
+    fn streams(&self) -> Result<HashMap<String, Sink>> {
+        self.inner.streams()
+    }
+
+    fn stream(&self, name: &str, args: &Tuple) -> Result<Sink> {
+        self.inner.stream(name, args)
+    }
+
+    fn concurrency(&self) -> Concurrency {
+        self.inner.concurrency()
+    }
+
+    fn leader(&self) -> bool {
+        self.inner.leader()
+    }
+
+    fn leader_streams(&self) -> Result<HashMap<String, Sink>> {
+        self.inner.leader_streams()
+    }
+
+    async fn dispatch(&self, request: Request) -> Result<Output> {
+        let received = SystemTime::now();
+        let recorded = request.clone();
+        let ret = self.inner.dispatch(request).await;
+
+        let response = Response::new(recorded.id.clone(), recorded.codec(), ret.clone());
+        let record = Record::new(received, recorded, response);
+        if let Err(err) = self.recorder.record(&record).await {
+            log::error!("failed to record call '{}': {}", record.request.id, err);
+        }
+
+        ret
+    }
+}
+
+/// read all records from a recording file
+pub async fn records<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<Record>> {
+    let file = File::open(path.as_ref())
+        .await
+        .with_context(|| format!("failed to open '{}'", path.as_ref().display()))?;
+
+    let mut lines = BufReader::new(file).lines();
+    let mut records = vec![];
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let mut record: Record = serde_json::from_str(&line).context("failed to decode record")?;
+        record.request.inputs.set_codec(record.codec);
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+/// A recorded call that produced a different response when replayed
+#[derive(Debug)]
+pub struct Mismatch {
+    pub record: Record,
+    pub response: Response,
+}
+
+/// Report of a replay run
+#[derive(Debug, Default)]
+pub struct Report {
+    /// number of replayed calls
+    pub total: usize,
+    /// calls with different responses
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl Report {
+    /// true if all replayed calls produced the recorded responses
+    pub fn ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// Replayer re-issues recorded requests against a module and compares
+/// the responses with the recorded ones.
+pub struct Replayer {
+    client: Client,
+    module: String,
+}
+
+impl Replayer {
+    pub fn new<S: Into<String>>(client: Client, module: S) -> Replayer {
+        Replayer {
+            client,
+            module: module.into(),
+        }
+    }
+
+    /// replay all records in the recording file at path
+    pub async fn replay<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<Report> {
+        let mut report = Report::default();
+        for record in records(path).await? {
+            report.total += 1;
+            if let Some(mismatch) = self.replay_record(record).await {
+                report.mismatches.push(mismatch);
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// replay a single record, returns a Mismatch if the response
+    /// is different from the recorded one
+    pub async fn replay_record(&self, record: Record) -> Option<Mismatch> {
+        // the recorded request is sent as is, only with a new id so the
+        // response does not collide with the response keys of the recording.
+        let mut request = record.request.clone();
+        request.id = uuid::Uuid::new_v4().to_string();
+        request.reply_to = request.id.clone();
+
+        let id = request.id.clone();
+        let response = match self.client.request(&self.module, request).await {
+            Err(Error::Protocol(err)) => Response {
+                id,
+                output: Default::default(),
+                error: Some(err),
+                more: false,
+            },
+            ret => Response::new(id, record.codec, ret),
+        };
+
+        if response.output == record.response.output && response.error == record.response.error {
+            return None;
+        }
+
+        Some(Mismatch { record, response })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::Sender;
+
+    struct Echo;
+
+    #[async_trait]
+    impl Object for Echo {
+        fn id(&self) -> ObjectID {
+            ObjectID::new("echo", "1.0")
+        }
+
+        fn streams(&self) -> Result<HashMap<String, Sink>> {
+            Ok(HashMap::default())
+        }
+
+        fn concurrency(&self) -> Concurrency {
+            Concurrency::serialized()
+        }
+
+        fn leader(&self) -> bool {
+            true
+        }
+
+        fn leader_streams(&self) -> Result<HashMap<String, Sink>> {
+            let (_, sink) = Sender::<u64>::new();
+            Ok(HashMap::from([("ticks".into(), sink)]))
+        }
+
+        async fn dispatch(&self, request: Request) -> Result<Output> {
+            match request.method.as_str() {
+                "echo" => Ok(Result::<String>::Ok(request.inputs.at(0)?).into()),
+                _ => Err(Error::UnknownMethod(request.method)),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn record() {
+        let path = std::env::temp_dir().join(format!("rbus-{}.rec", uuid::Uuid::new_v4()));
+        let recorder = Recorder::new(&path).await.unwrap();
+        let object = recorder.wrap(Echo);
+
+        let request = Request::new(object.id(), "echo").arg("hello").unwrap();
+        object.dispatch(request).await.unwrap();
+        let request = Request::new(object.id(), "unknown");
+        assert!(object.dispatch(request).await.is_err());
+
+        let records = records(&path).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].request.method, "echo");
+        assert_eq!(records[0].request.inputs.at::<String>(0).unwrap(), "hello");
+        assert!(records[0].response.error.is_none());
+        assert!(records[1].response.error.is_some());
+    }
+
+    #[tokio::test]
+    async fn forward() {
+        let path = std::env::temp_dir().join(format!("rbus-{}.rec", uuid::Uuid::new_v4()));
+        let recorder = Recorder::new(&path).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let object = recorder.wrap(Echo);
+        assert_eq!(object.concurrency(), Concurrency::serialized());
+        assert!(object.leader());
+        let streams = object.leader_streams().unwrap();
+        assert!(streams.contains_key("ticks"));
+    }
+}
@@ -0,0 +1,115 @@
+//! Retry policy of the client.
+use std::time::Duration;
+
+/// wait before the first retry
+const BACKOFF: Duration = Duration::from_millis(100);
+/// max wait between retries
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Kind of failure of a call attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// no response within the client timeout (see [`crate::Client::with_timeout`])
+    Timeout,
+    /// the request could not be sent, or the response could not be received
+    Connection,
+    /// the call returned an error
+    Call,
+}
+
+/// Retry policy of the client. Only idempotent requests (see
+/// [`crate::protocol::Request::idempotent`] and `#[idempotent]` on object methods)
+/// are retried. The server runs an idempotent request once, the retries of a request
+/// that already ran are answered with its response. A request that failed (see
+/// [`Failure::Call`]) runs again when it's retried.
+///
+/// An attempt only times out if the client has a timeout, so retrying on
+/// [`Failure::Timeout`] needs [`crate::Client::with_timeout`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Retry {
+    attempts: usize,
+    backoff: Duration,
+    max_backoff: Duration,
+    on: Vec<Failure>,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry::never()
+    }
+}
+
+impl Retry {
+    /// requests are sent once, this is the default
+    pub fn never() -> Retry {
+        Retry {
+            attempts: 1,
+            backoff: BACKOFF,
+            max_backoff: MAX_BACKOFF,
+            on: vec![Failure::Timeout, Failure::Connection],
+        }
+    }
+
+    /// make up to attempts attempts (including the first one). Timeouts and connection
+    /// failures are retried, the first retry waits 100ms and the wait doubles on each
+    /// retry up to 5s
+    pub fn new(attempts: usize) -> Retry {
+        assert!(attempts >= 1, "attempts must be at least 1");
+        Retry {
+            attempts,
+            ..Retry::never()
+        }
+    }
+
+    /// wait backoff before the first retry, the wait doubles on each retry up to max
+    pub fn backoff(mut self, backoff: Duration, max: Duration) -> Retry {
+        self.backoff = backoff;
+        self.max_backoff = max;
+        self
+    }
+
+    /// the kinds of failures that are retried
+    pub fn on(mut self, failures: &[Failure]) -> Retry {
+        self.on = failures.to_vec();
+        self
+    }
+
+    /// true if the request is attempted again after attempt (starting at 1) failed
+    pub(crate) fn retries(&self, attempt: usize, failure: Failure) -> bool {
+        attempt < self.attempts && self.on.contains(&failure)
+    }
+
+    /// the wait before the retry that follows attempt (starting at 1)
+    pub(crate) fn delay(&self, attempt: usize) -> Duration {
+        let exp = attempt.saturating_sub(1).min(u32::MAX as usize) as u32;
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(exp))
+            .min(self.max_backoff)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retry() {
+        let retry = Retry::new(3);
+        assert!(retry.retries(1, Failure::Timeout));
+        assert!(retry.retries(2, Failure::Connection));
+        assert!(!retry.retries(3, Failure::Timeout));
+        assert!(!retry.retries(1, Failure::Call));
+        assert!(!Retry::never().retries(1, Failure::Timeout));
+
+        let retry = Retry::new(10)
+            .backoff(Duration::from_secs(1), Duration::from_secs(5))
+            .on(&[Failure::Call]);
+        assert!(retry.retries(1, Failure::Call));
+        assert!(!retry.retries(1, Failure::Timeout));
+        assert_eq!(retry.delay(1), Duration::from_secs(1));
+        assert_eq!(retry.delay(2), Duration::from_secs(2));
+        assert_eq!(retry.delay(3), Duration::from_secs(4));
+        assert_eq!(retry.delay(4), Duration::from_secs(5));
+        assert_eq!(retry.delay(100), Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,116 @@
+//! Deduplication of idempotent requests.
+//!
+//! A request with an idempotency key runs once. The server records the replica that
+//! runs the request, then its response, under `{module}.$dedup.{key}` for `DEDUP_TTL`.
+//! The retries of the request are answered with the recorded response, or dropped while
+//! the request is still running since the running call answers the same request id.
+//! Failed responses are not recorded, the retries of a failed request run it again.
+//! The record lives in redis so the retries that are pulled by another replica are
+//! also deduplicated.
+use super::redis::RESPONSE_TTL;
+use crate::protocol::Response;
+use bb8_redis::{
+    bb8::Pool,
+    redis::{self, FromRedisValue},
+    RedisConnectionManager,
+};
+
+/// responses of idempotent requests are kept as long as the responses themselves
+const DEDUP_TTL: usize = RESPONSE_TTL;
+const REPLICA: &str = "Replica";
+const RESPONSE: &str = "Response";
+
+/// delete the record (KEYS[1]) if its replica field (ARGV[2]) is set to the replica
+/// (ARGV[1]) and it has no response field (ARGV[3])
+const ABANDON: &str = r#"
+if redis.call('HGET', KEYS[1], ARGV[2]) == ARGV[1]
+    and redis.call('HEXISTS', KEYS[1], ARGV[3]) == 0 then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+/// what the server knows about an idempotent request
+pub(crate) enum Seen {
+    /// first time, the request must run
+    New,
+    /// the request is running
+    Running,
+    /// the request ran, with this response
+    Done(Response),
+}
+
+fn dedup_key(module: &str, key: &str) -> String {
+    format!("{}.$dedup.{}", module, key)
+}
+
+/// record that replica runs the request with idempotency key, unless the request
+/// already ran or is running
+pub(crate) async fn begin(
+    pool: &Pool<RedisConnectionManager>,
+    module: &str,
+    replica: &str,
+    key: &str,
+) -> anyhow::Result<Seen> {
+    let key = dedup_key(module, key);
+    let mut con = pool.get().await?;
+    let (new, response): (bool, Option<Vec<u8>>) = redis::pipe()
+        .atomic()
+        .hset_nx(&key, REPLICA, replica)
+        .expire(&key, DEDUP_TTL)
+        .ignore()
+        .hget(&key, RESPONSE)
+        .query_async(&mut *con)
+        .await?;
+
+    Ok(match (new, response) {
+        (true, _) => Seen::New,
+        (false, None) => Seen::Running,
+        (false, Some(data)) => Seen::Done(Response::from_redis_value(&redis::Value::Data(data))?),
+    })
+}
+
+/// record the response of the request with idempotency key that replica ran. If the
+/// request failed it's forgotten instead, so a retry runs it again
+pub(crate) async fn finish(
+    pool: &Pool<RedisConnectionManager>,
+    module: &str,
+    replica: &str,
+    key: &str,
+    response: &Response,
+) -> anyhow::Result<()> {
+    if response.error.is_some() || response.output.error.is_some() {
+        return abandon(pool, module, replica, key).await;
+    }
+
+    let key = dedup_key(module, key);
+    let mut con = pool.get().await?;
+    redis::pipe()
+        .atomic()
+        .hset(&key, RESPONSE, response)
+        .expire(&key, DEDUP_TTL)
+        .query_async::<_, ()>(&mut *con)
+        .await?;
+    Ok(())
+}
+
+/// forget the request with idempotency key if replica was running it, so the
+/// request runs again once it's requeued (the replica is gone) or retried
+pub(crate) async fn abandon(
+    pool: &Pool<RedisConnectionManager>,
+    module: &str,
+    replica: &str,
+    key: &str,
+) -> anyhow::Result<()> {
+    let mut con = pool.get().await?;
+    redis::cmd("EVAL")
+        .arg(ABANDON)
+        .arg(1)
+        .arg(dedup_key(module, key))
+        .arg(replica)
+        .arg(REPLICA)
+        .arg(RESPONSE)
+        .query_async::<_, ()>(&mut *con)
+        .await?;
+    Ok(())
+}
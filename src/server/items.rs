@@ -0,0 +1,109 @@
+//! Results of calls that are streamed to the caller.
+use super::redis::RESPONSE_TTL;
+use crate::chunked::{self, MAX_PENDING};
+use crate::protocol::{Error, Output, Request, Response, Result};
+use bb8_redis::redis::AsyncCommands;
+use futures_util::{stream, Stream, StreamExt};
+use serde::Serialize;
+use std::fmt::Display;
+use std::pin::Pin;
+
+/// Items is returned by calls that stream their results to the caller. The items
+/// are pushed to the reply key of the request one by one as they are produced, and
+/// the generated stub returns a [`crate::client::Receiver`] of the items.
+///
+/// Unlike `#[stream]` events, the items are private to the caller of the request.
+pub struct Items<T> {
+    inner: Pin<Box<dyn Stream<Item = anyhow::Result<T>> + Send>>,
+}
+
+impl<T> Items<T>
+where
+    T: Serialize + Send + 'static,
+{
+    /// stream the items of s
+    pub fn new<S>(s: S) -> Self
+    where
+        S: Stream<Item = T> + Send + 'static,
+    {
+        Items {
+            inner: Box::pin(s.map(Ok)),
+        }
+    }
+
+    /// stream the items of s. The caller receives the error and the items end
+    /// at the first error.
+    pub fn try_new<S, E>(s: S) -> Self
+    where
+        S: Stream<Item = std::result::Result<T, E>> + Send + 'static,
+        E: Into<anyhow::Error>,
+    {
+        Items {
+            inner: Box::pin(s.map(|item| item.map_err(Into::into))),
+        }
+    }
+
+    /// push the items of a call result to the caller. The returned output is the
+    /// final response that ends the items (or carries the call error).
+    #[doc(hidden)]
+    pub async fn reply<E>(request: &Request, items: std::result::Result<Self, E>) -> Result<Output>
+    where
+        E: Display,
+    {
+        let codec = request.codec();
+        let mut items = match items {
+            Ok(items) => items,
+            Err(err) => return Ok(Output::with_codec(codec, Err::<(), E>(err))),
+        };
+
+        let pool = chunked::pool()?;
+        while let Some(item) = items.inner.next().await {
+            let output = Output::with_codec(codec, item);
+            if output.error.is_some() {
+                return Ok(output);
+            }
+
+            let mut con = pool.get().await.map_err(|err| {
+                Error::Protocol(format!("failed to get redis connection: {}", err))
+            })?;
+
+            let pending: usize = con
+                .rpush(&request.id, Response::item(&request.id, output))
+                .await
+                .map_err(|err| Error::Protocol(format!("failed to push item: {}", err)))?;
+            let _ = con.expire::<_, ()>(&request.id, RESPONSE_TTL).await;
+
+            // wait for the caller to catch up
+            if pending >= MAX_PENDING {
+                chunked::drain(&pool, &request.id).await?;
+            }
+        }
+
+        Ok(Output::with_codec(codec, Ok::<_, Error>(())))
+    }
+}
+
+impl<T> From<Vec<T>> for Items<T>
+where
+    T: Serialize + Send + 'static,
+{
+    fn from(items: Vec<T>) -> Self {
+        Items::new(stream::iter(items))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::ObjectID;
+
+    #[tokio::test]
+    async fn failed() {
+        let request = Request::new(ObjectID::new("files", "1.0"), "list");
+        let items: std::result::Result<Items<u32>, _> = Err("no such directory");
+
+        let output = Items::reply(&request, items).await.unwrap();
+        let result: Result<()> = output.into();
+        assert!(matches!(result, Err(Error::Call(err)) if err.message == "no such directory"));
+    }
+}
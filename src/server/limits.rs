@@ -0,0 +1,176 @@
+//! Concurrency limits of objects and their methods.
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Concurrency limits of an object. A limit is the number of calls to the object (or
+/// to one of its methods) that can run at the same time. Calls are also limited by the
+/// number of workers of the module.
+///
+/// Limits are declared with `#[object(concurrency = N)]` on the trait and `#[concurrency(N)]`
+/// on a method, or given to [`crate::Server::register_with`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Concurrency {
+    object: Option<usize>,
+    methods: HashMap<String, usize>,
+}
+
+impl Concurrency {
+    /// no limits other than the module workers
+    pub fn unlimited() -> Concurrency {
+        Concurrency::default()
+    }
+
+    /// at most limit calls to the object at the same time
+    pub fn new(limit: usize) -> Concurrency {
+        assert!(limit >= 1, "concurrency limit must be at least 1");
+        Concurrency {
+            object: Some(limit),
+            methods: HashMap::new(),
+        }
+    }
+
+    /// one call at a time, for objects that need exclusive access to a resource
+    pub fn serialized() -> Concurrency {
+        Concurrency::new(1)
+    }
+
+    /// at most limit calls to the method with name at the same time
+    pub fn method<S: Into<String>>(mut self, name: S, limit: usize) -> Concurrency {
+        assert!(limit >= 1, "concurrency limit must be at least 1");
+        self.methods.insert(name.into(), limit);
+        self
+    }
+}
+
+/// enforces the concurrency limits of a registered object
+pub(crate) struct Limits {
+    object: Option<Arc<Semaphore>>,
+    methods: HashMap<String, Arc<Semaphore>>,
+    // the limited method the next call of the object is waiting for
+    waiting: Option<Arc<Semaphore>>,
+}
+
+impl From<Concurrency> for Limits {
+    fn from(concurrency: Concurrency) -> Self {
+        Limits {
+            object: concurrency.object.map(|n| Arc::new(Semaphore::new(n))),
+            methods: concurrency
+                .methods
+                .into_iter()
+                .map(|(name, n)| (name, Arc::new(Semaphore::new(n))))
+                .collect(),
+            waiting: None,
+        }
+    }
+}
+
+impl Limits {
+    /// true if the object can take one more call, and the method its next call is
+    /// waiting for (if any) can take it too
+    pub(crate) fn available(&self) -> bool {
+        [&self.object, &self.waiting]
+            .iter()
+            .filter_map(|semaphore| semaphore.as_ref())
+            .all(|semaphore| semaphore.available_permits() > 0)
+    }
+
+    /// take a call slot of the object and of the method with name without waiting. None
+    /// if the object or the method is running its max number of calls. If it's the method,
+    /// the object is then not available until the method can take a call.
+    pub(crate) fn try_acquire(&mut self, method: &str) -> Option<Permit> {
+        let object = match &self.object {
+            Some(semaphore) => Some(Slot::try_acquire(semaphore)?),
+            None => None,
+        };
+
+        let method = match self.methods.get(method) {
+            Some(semaphore) => match Slot::try_acquire(semaphore) {
+                Some(slot) => Some(slot),
+                None => {
+                    self.waiting = Some(Arc::clone(semaphore));
+                    return None;
+                }
+            },
+            None => None,
+        };
+
+        self.waiting = None;
+        Some(Permit { object, method })
+    }
+}
+
+/// a taken call slot
+struct Slot {
+    semaphore: Arc<Semaphore>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Slot {
+    fn try_acquire(semaphore: &Arc<Semaphore>) -> Option<Slot> {
+        Arc::clone(semaphore)
+            .try_acquire_owned()
+            .ok()
+            .map(|permit| Slot {
+                semaphore: Arc::clone(semaphore),
+                _permit: permit,
+            })
+    }
+}
+
+/// the call slots of an object and its method, they are released once dropped
+#[derive(Default)]
+pub(crate) struct Permit {
+    object: Option<Slot>,
+    method: Option<Slot>,
+}
+
+impl Permit {
+    /// release the slots. returns true if the object or the method was running the
+    /// max number of calls (the requests of the object were not pulled)
+    pub(crate) fn release(self) -> bool {
+        let saturated = [&self.object, &self.method]
+            .iter()
+            .filter_map(|slot| slot.as_ref())
+            .any(|slot| slot.semaphore.available_permits() == 0);
+        drop(self);
+        saturated
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limits() {
+        let mut limits = Limits::from(Concurrency::new(2).method("write", 1));
+        assert!(limits.available());
+
+        let first = limits.try_acquire("read").unwrap();
+        let second = limits.try_acquire("read").unwrap();
+        assert!(!limits.available());
+        assert!(second.object.is_some());
+        assert!(limits.try_acquire("read").is_none());
+
+        assert!(first.release());
+        assert!(limits.available());
+        assert!(!second.release());
+
+        // the object waits for a method that is running its max number of calls
+        let write = limits.try_acquire("write").unwrap();
+        assert!(limits.try_acquire("write").is_none());
+        assert!(!limits.available());
+        assert!(write.release());
+        assert!(limits.available());
+        let _write = limits.try_acquire("write").unwrap();
+        assert!(limits.try_acquire("read").is_some());
+        assert!(limits.available());
+
+        let mut unlimited = Limits::from(Concurrency::unlimited());
+        assert!(unlimited.available());
+        let permit = unlimited.try_acquire("read").unwrap();
+        assert!(permit.object.is_none() && permit.method.is_none());
+        assert!(!permit.release());
+    }
+}
@@ -1,45 +1,231 @@
+use crate::chunked;
+use crate::introspect::StreamStatus;
+use crate::pipe::{self, Pipe};
 use crate::protocol::{self, Error, ObjectID, Output, Request, Result, Tuple};
 use async_trait::async_trait;
-use serde::Serialize;
+use serde::de::{self as de, DeserializeOwned, Deserializer};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 use std::collections::HashMap;
 use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 use thiserror::Error;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+mod dedup;
+mod items;
+mod limits;
+mod queue;
 pub mod redis;
-pub use self::redis::Server;
+mod replica;
+mod supervisor;
+pub use self::items::Items;
+pub use self::limits::Concurrency;
+pub use self::queue::{Overflow, DEFAULT_BUFFER};
+use self::queue::{QueueReceiver, QueueSender};
+pub use self::redis::{Server, ServerHandle};
+pub use self::supervisor::Restart;
 
 /// Sender is used by streams to publish events.
+///
+/// A Sender can also be an argument of a call (that is not a stream) taking a [`Stream`]
+/// argument. The items are then sent back to the client that made the call, and the client
+/// receives them with a [`crate::client::Session`]. The client is notified once all senders
+/// are dropped.
 pub struct Sender<T> {
-    tx: mpsc::Sender<serde_bytes::ByteBuf>,
+    tx: QueueSender,
+    observers: watch::Receiver<usize>,
     p: PhantomData<T>,
 }
 
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            observers: self.observers.clone(),
+            p: PhantomData,
+        }
+    }
+}
+
 impl<T> Sender<T>
 where
     T: Serialize,
 {
     /// create a new Sender, Sink pair. a
     pub fn new() -> (Self, Sink) {
-        let (tx, rx) = mpsc::channel(5);
-        (Self { tx, p: PhantomData }, Sink { rx })
+        Self::with_buffer(DEFAULT_BUFFER, Overflow::Block)
+    }
+
+    /// create a new Sender, Sink pair that buffers up to size events. The overflow
+    /// policy decides what happens when an event is sent while the buffer is full
+    /// (for example if publishing the events is slow).
+    pub fn with_buffer(size: usize, overflow: Overflow) -> (Self, Sink) {
+        let status: Arc<Mutex<StreamStatus>> = Arc::default();
+        let (tx, rx) = queue::channel(size, overflow, Arc::clone(&status));
+        let (observers_tx, observers) = watch::channel(0);
+        (
+            Self {
+                tx,
+                observers,
+                p: PhantomData,
+            },
+            Sink {
+                rx,
+                task: None,
+                observers: observers_tx,
+                status,
+            },
+        )
+    }
+
+    /// number of clients subscribed to the stream. The server polls the
+    /// subscribers periodically, so it can lag for a few seconds.
+    pub fn observers(&self) -> usize {
+        *self.observers.borrow()
+    }
+
+    /// true if at least one client is subscribed to the stream. Expensive
+    /// producers can use it to skip events nobody is listening to.
+    pub fn is_observed(&self) -> bool {
+        self.observers() > 0
+    }
+
+    /// wait until at least one client is subscribed to the stream. Returns
+    /// immediately if the stream is already observed.
+    pub async fn wait_for_subscribers(&self) {
+        let mut observers = self.observers.clone();
+        while *observers.borrow() == 0 {
+            if observers.changed().await.is_err() {
+                // the sink is gone, there will never be subscribers
+                return std::future::pending().await;
+            }
+        }
+    }
+
+    /// number of events dropped because the buffer was full
+    pub fn dropped(&self) -> u64 {
+        self.tx.dropped()
     }
 
-    /// send pushed object T as event
+    /// send pushed object T as event. Events are always encoded with
+    /// MessagePack whatever the codec of the subscribers (see [`protocol::Codec`])
     pub async fn send(&self, msg: &T) -> anyhow::Result<()> {
         let msg = protocol::encode(msg)?;
         Ok(self.tx.send(msg).await?)
     }
 }
 
+impl<'de, T> Deserialize<'de> for Sender<T>
+where
+    T: Serialize,
+{
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let pipe = Pipe::deserialize(deserializer)?;
+        let pool = chunked::pool().map_err(de::Error::custom)?;
+        let (sender, mut sink) = Sender::new();
+        tokio::spawn(async move {
+            let mut result = Ok(());
+            while let Some(item) = sink.recv().await {
+                result = pipe.send(&pool, item).await;
+                if result.is_err() {
+                    break;
+                }
+            }
+
+            pipe.close(&pool, result).await;
+        });
+
+        Ok(sender)
+    }
+}
+
+/// Stream of the items sent by the client during a call. A call that takes a Stream
+/// argument is a client-streaming call (or a bidirectional one if it also takes a [`Sender`]).
+/// The stream ends once the client is done sending.
+pub struct Stream<T> {
+    rx: mpsc::Receiver<Result<ByteBuf>>,
+    p: PhantomData<fn() -> T>,
+}
+
+impl<T> Stream<T>
+where
+    T: DeserializeOwned,
+{
+    /// receive the next item. returns None once the client is done sending
+    pub async fn recv(&mut self) -> Option<Result<T>> {
+        self.rx.recv().await.map(pipe::decode)
+    }
+}
+
+impl<T> futures_util::Stream for Stream<T>
+where
+    T: DeserializeOwned,
+{
+    type Item = Result<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx).map(|item| item.map(pipe::decode))
+    }
+}
+
+impl<'de, T> Deserialize<'de> for Stream<T> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let pipe = Pipe::deserialize(deserializer)?;
+        let pool = chunked::pool().map_err(de::Error::custom)?;
+
+        Ok(Stream {
+            rx: pipe.reader(pool),
+            p: PhantomData,
+        })
+    }
+}
+
 /// Sink is the receiver part of a event Sender. used internally by rbus
 pub struct Sink {
-    pub rx: mpsc::Receiver<serde_bytes::ByteBuf>,
+    rx: QueueReceiver,
+    task: Option<JoinHandle<()>>,
+    observers: watch::Sender<usize>,
+    status: Arc<Mutex<StreamStatus>>,
 }
 
 impl Sink {
-    async fn recv(&mut self) -> Option<serde_bytes::ByteBuf> {
+    /// attach the task that produces the events of this sink. The task
+    /// is aborted when the sink is dropped (no more subscribers)
+    pub fn attach(&mut self, task: JoinHandle<()>) {
+        self.task = Some(task);
+    }
+
+    /// receive the next event. returns None once the producer is gone
+    pub async fn recv(&mut self) -> Option<serde_bytes::ByteBuf> {
         self.rx.recv().await
     }
+
+    /// status of the stream producer
+    pub(crate) fn status(&self) -> Arc<Mutex<StreamStatus>> {
+        Arc::clone(&self.status)
+    }
+
+    /// update the number of clients subscribed to the stream
+    pub(crate) fn set_observers(&self, observers: usize) {
+        let _ = self.observers.send(observers);
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
 }
 
 /// Object trait
@@ -49,10 +235,76 @@ pub trait Object {
     fn id(&self) -> ObjectID;
     fn streams(&self) -> Result<HashMap<String, Sink>>;
 
+    /// start the parameterized stream with name for the given arguments. It's
+    /// called when the first client subscribes to this set of arguments and the
+    /// returned Sink is dropped once the last subscriber is gone.
+    fn stream(&self, name: &str, _args: &Tuple) -> Result<Sink> {
+        Err(Error::UnknownStream(name.into()))
+    }
+
+    /// concurrency limits of the object, see [`Concurrency`]. Unlimited by default
+    fn concurrency(&self) -> Concurrency {
+        Concurrency::unlimited()
+    }
+
+    /// true if the object only runs on the leader replica of the module, its calls are
+    /// only served and its streams only published by the leader. False by default
+    fn leader(&self) -> bool {
+        false
+    }
+
+    /// start the streams that only run on the leader replica of the module. They are
+    /// started once the replica becomes the leader, and the returned sinks are dropped
+    /// once it's not the leader anymore. Unlike [`Object::streams`] that are started
+    /// on all replicas.
+    fn leader_streams(&self) -> Result<HashMap<String, Sink>> {
+        Ok(HashMap::default())
+    }
+
     /// dispatch request and get an Output
     async fn dispatch(&self, request: Request) -> Result<Output>;
 }
 
+/// a named instance of an object, it only changes the object id
+struct Instance<T> {
+    id: ObjectID,
+    object: T,
+}
+
+#[async_trait]
+impl<T> Object for Instance<T>
+where
+    T: Object + Send + Sync,
+{
+    fn id(&self) -> ObjectID {
+        self.id.clone()
+    }
+
+    fn streams(&self) -> Result<HashMap<String, Sink>> {
+        self.object.streams()
+    }
+
+    fn stream(&self, name: &str, args: &Tuple) -> Result<Sink> {
+        self.object.stream(name, args)
+    }
+
+    fn concurrency(&self) -> Concurrency {
+        self.object.concurrency()
+    }
+
+    fn leader(&self) -> bool {
+        self.object.leader()
+    }
+
+    fn leader_streams(&self) -> Result<HashMap<String, Sink>> {
+        self.object.leader_streams()
+    }
+
+    async fn dispatch(&self, request: Request) -> Result<Output> {
+        self.object.dispatch(request).await
+    }
+}
+
 /// Handlers must implement this trait
 #[async_trait]
 pub trait Handler {
@@ -105,3 +357,36 @@ impl Object for SimpleObject {
         Ok(HashMap::default())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn observed() {
+        let (sender, sink) = Sender::<u32>::new();
+        assert!(!sender.is_observed());
+
+        let wait = tokio::time::timeout(Duration::from_millis(50), sender.wait_for_subscribers());
+        assert!(wait.await.is_err());
+
+        sink.set_observers(2);
+        assert!(sender.is_observed());
+        assert_eq!(sender.observers(), 2);
+
+        let wait = tokio::time::timeout(Duration::from_millis(50), sender.wait_for_subscribers());
+        assert!(wait.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn events() {
+        let (sender, mut sink) = Sender::<String>::new();
+        sender.send(&"hello".into()).await.unwrap();
+
+        // json is only used for calls, events are always msgpack
+        let event = sink.recv().await.unwrap();
+        assert_eq!(event, protocol::Codec::MsgPack.encode("hello").unwrap());
+        assert!(protocol::Codec::Json.decode::<String>(&event).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+//! Bounded event queue between a stream Sender and its Sink.
+use crate::introspect::StreamStatus;
+use serde_bytes::ByteBuf;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::sync::Notify;
+
+/// default number of events buffered by a stream
+pub const DEFAULT_BUFFER: usize = 5;
+
+/// What happens when an event is sent while the stream buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// wait until there is room in the buffer
+    #[default]
+    Block,
+    /// drop the oldest buffered event to make room for the new one
+    DropOldest,
+    /// drop the new event
+    DropNewest,
+    /// replace the latest buffered event with the new one
+    CoalesceLatest,
+}
+
+#[derive(Error, Debug)]
+#[error("stream is closed")]
+pub struct Closed;
+
+struct State {
+    events: VecDeque<ByteBuf>,
+    senders: usize,
+    closed: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    overflow: Overflow,
+    // notified when an event is pushed or all senders are gone
+    pushed: Notify,
+    // notified when an event is popped or the receiver is gone
+    popped: Notify,
+    status: Arc<Mutex<StreamStatus>>,
+}
+
+impl Shared {
+    fn dropped(&self) {
+        if let Ok(mut status) = self.status.lock() {
+            status.dropped += 1;
+        }
+    }
+}
+
+pub(crate) fn channel(
+    capacity: usize,
+    overflow: Overflow,
+    status: Arc<Mutex<StreamStatus>>,
+) -> (QueueSender, QueueReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            events: VecDeque::with_capacity(capacity),
+            senders: 1,
+            closed: false,
+        }),
+        capacity: std::cmp::max(capacity, 1),
+        overflow,
+        pushed: Notify::new(),
+        popped: Notify::new(),
+        status,
+    });
+
+    (
+        QueueSender {
+            shared: Arc::clone(&shared),
+        },
+        QueueReceiver { shared },
+    )
+}
+
+pub(crate) struct QueueSender {
+    shared: Arc<Shared>,
+}
+
+impl QueueSender {
+    pub fn dropped(&self) -> u64 {
+        self.shared
+            .status
+            .lock()
+            .map(|status| status.dropped)
+            .unwrap_or_default()
+    }
+
+    pub async fn send(&self, event: ByteBuf) -> Result<(), Closed> {
+        let mut event = Some(event);
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if state.closed {
+                    return Err(Closed);
+                }
+
+                if state.events.len() < self.shared.capacity {
+                    state.events.extend(event.take());
+                    self.shared.pushed.notify_one();
+                    return Ok(());
+                }
+
+                match self.shared.overflow {
+                    Overflow::Block => {}
+                    Overflow::DropOldest => {
+                        state.events.pop_front();
+                        state.events.extend(event.take());
+                        self.shared.dropped();
+                        self.shared.pushed.notify_one();
+                        return Ok(());
+                    }
+                    Overflow::DropNewest => {
+                        self.shared.dropped();
+                        return Ok(());
+                    }
+                    Overflow::CoalesceLatest => {
+                        if let (Some(latest), Some(event)) = (state.events.back_mut(), event.take())
+                        {
+                            *latest = event;
+                        }
+                        self.shared.dropped();
+                        return Ok(());
+                    }
+                }
+            }
+
+            // a pop stores a permit if nobody is waiting, so an event popped
+            // after the lock is released is not missed.
+            self.shared.popped.notified().await;
+        }
+    }
+}
+
+impl Clone for QueueSender {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl Drop for QueueSender {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.shared.pushed.notify_one();
+        }
+    }
+}
+
+pub(crate) struct QueueReceiver {
+    shared: Arc<Shared>,
+}
+
+impl QueueReceiver {
+    /// receive the next event. returns None once all senders are gone
+    /// and the buffer is empty.
+    pub async fn recv(&mut self) -> Option<ByteBuf> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(event) = state.events.pop_front() {
+                    self.shared.popped.notify_one();
+                    return Some(event);
+                }
+
+                if state.senders == 0 {
+                    return None;
+                }
+            }
+
+            self.shared.pushed.notified().await;
+        }
+    }
+}
+
+impl Drop for QueueReceiver {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.popped.notify_waiters();
+        self.shared.popped.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(v: u8) -> ByteBuf {
+        ByteBuf::from(vec![v])
+    }
+
+    async fn drain(mut rx: QueueReceiver) -> Vec<u8> {
+        let mut events = vec![];
+        while let Some(event) = rx.recv().await {
+            events.push(event[0]);
+        }
+        events
+    }
+
+    async fn overflow(overflow: Overflow) -> (Vec<u8>, u64) {
+        let status = Arc::default();
+        let (tx, rx) = channel(2, overflow, Arc::clone(&status));
+        for v in 0..4 {
+            tx.send(event(v)).await.unwrap();
+        }
+        drop(tx);
+
+        let dropped = status.lock().unwrap().dropped;
+        (drain(rx).await, dropped)
+    }
+
+    #[tokio::test]
+    async fn policies() {
+        assert_eq!(overflow(Overflow::DropOldest).await, (vec![2, 3], 2));
+        assert_eq!(overflow(Overflow::DropNewest).await, (vec![0, 1], 2));
+        assert_eq!(overflow(Overflow::CoalesceLatest).await, (vec![0, 3], 2));
+    }
+
+    #[tokio::test]
+    async fn block() {
+        let (tx, mut rx) = channel(1, Overflow::Block, Arc::default());
+        tx.send(event(0)).await.unwrap();
+
+        let send = tokio::spawn(async move {
+            tx.send(event(1)).await.unwrap();
+        });
+
+        assert_eq!(rx.recv().await.unwrap()[0], 0);
+        send.await.unwrap();
+        assert_eq!(drain(rx).await, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn closed() {
+        let (tx, rx) = channel(1, Overflow::Block, Arc::default());
+        tx.send(event(0)).await.unwrap();
+        drop(rx);
+        assert!(tx.send(event(1)).await.is_err());
+    }
+}
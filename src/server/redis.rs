@@ -1,28 +1,371 @@
-use super::{Error, Result};
-use super::{Object, Sink};
-use crate::protocol::{Output, Request, Response};
-use bb8_redis::{bb8::Pool, redis::AsyncCommands, RedisConnectionManager};
-use std::collections::HashMap;
-use std::sync::Arc;
+use super::dedup::{self, Seen};
+use super::limits::{Limits, Permit};
+use super::replica::{Replica, LEADER_RENEW};
+use super::{Concurrency, Error, Result};
+use super::{Instance, Object, Sink};
+use crate::chunked;
+use crate::introspect::{self, Health, ModuleInfo, ObjectInfo, StreamInfo, StreamStatus};
+use crate::protocol::{self, Kind, ObjectID, Output, Priority, Request, Response};
+use bb8_redis::{
+    bb8::Pool,
+    redis::{self, AsyncCommands, FromRedisValue},
+    RedisConnectionManager,
+};
+use serde_bytes::ByteBuf;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex as StdMutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::task::{Context, Poll};
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
 use tokio::time::{sleep, Duration};
+use uuid::Uuid;
 
 const PULL_TIMEOUT: usize = 10;
-const RESPONSE_TTL: usize = 5 * 60;
+pub(crate) const RESPONSE_TTL: usize = 5 * 60;
+/// how often the subscribers of streams are checked
+const OBSERVE_INTERVAL: Duration = Duration::from_secs(5);
+/// number of consecutive checks without subscribers before a
+/// parameterized stream producer is stopped
+const REAP_IDLE: usize = 2;
+/// delay before retrying to publish a stream event
+const PUBLISH_RETRY: Duration = Duration::from_secs(1);
 
-type Objects = HashMap<String, Box<dyn Object + Send + Sync>>;
+/// status of the streams of an object
+type StreamsStatus = Vec<(String, Arc<StdMutex<StreamStatus>>)>;
 
-/// Server module. for each module there should be
-/// only one instance of this server running. Each module
-/// can has multiple registered objects.
+/// a registered object
+struct Entry {
+    object: Arc<dyn Object + Send + Sync>,
+    // status of the object streams, set once the streams are started
+    streams: StreamsStatus,
+    // the object only runs on the leader replica
+    leader: bool,
+    // the streams that only run on the leader, set while this replica is the leader
+    term: Option<Term>,
+    // other versions served by the object
+    versions: Vec<String>,
+    limits: Limits,
+    // scheduling weight and credit of the object, see Registry::queues
+    weight: i64,
+    credit: i64,
+    // the stream producers of the object stop once the entry is dropped
+    _alive: watch::Sender<()>,
+    closed: watch::Receiver<()>,
+}
+
+/// the streams of an object that run while this replica is the leader, they
+/// stop once the term is dropped
+struct Term {
+    streams: StreamsStatus,
+    _alive: watch::Sender<()>,
+}
+
+/// objects served by a module
+#[derive(Default)]
+struct Registry {
+    objects: HashMap<String, Entry>,
+    // other versions served by a registered object (alias -> object)
+    aliases: HashMap<String, String>,
+    // this replica is the leader of the module
+    leading: bool,
+}
+
+impl Registry {
+    fn register(
+        &mut self,
+        object: Arc<dyn Object + Send + Sync>,
+        concurrency: Concurrency,
+    ) -> Result<()> {
+        let key = object.id().to_string();
+        self.reserve(&key)?;
+
+        let (alive, closed) = watch::channel(());
+        let leader = object.leader();
+        self.objects.insert(
+            key,
+            Entry {
+                object,
+                streams: vec![],
+                leader,
+                term: None,
+                versions: vec![],
+                limits: concurrency.into(),
+                weight: 1,
+                credit: 0,
+                _alive: alive,
+                closed,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// fails if key is already used by an object or an alias
+    fn reserve(&self, key: &str) -> Result<()> {
+        if self.objects.contains_key(key) || self.aliases.contains_key(key) {
+            return Err(Error::AlreadyRegistered(key.into()));
+        }
+
+        Ok(())
+    }
+
+    fn alias(&mut self, id: &ObjectID, version: &str) -> Result<()> {
+        let key = id.to_string();
+        if !self.objects.contains_key(&key) {
+            return Err(Error::UnknownObject(key));
+        }
+
+        let compatible = match (
+            protocol::parse_version_req(format!("^{}", version)),
+            id.semver(),
+        ) {
+            (Ok(req), Some(current)) => req.matches(&current),
+            _ => false,
+        };
+
+        if !compatible {
+            return Err(Error::Protocol(format!(
+                "version '{}' is not compatible with object '{}'",
+                version, key
+            )));
+        }
+
+        let alias = ObjectID {
+            version: version.into(),
+            ..id.clone()
+        }
+        .to_string();
+        self.reserve(&alias)?;
+
+        if let Some(entry) = self.objects.get_mut(&key) {
+            entry.versions.push(version.into());
+        }
+        self.aliases.insert(alias, key);
+        Ok(())
+    }
+
+    /// remove the object with id and its aliases
+    fn unregister(&mut self, id: &ObjectID) -> Result<Entry> {
+        let key = id.to_string();
+        let entry = self
+            .objects
+            .remove(&key)
+            .ok_or_else(|| Error::UnknownObject(key.clone()))?;
+        self.aliases.retain(|_, object| *object != key);
+
+        Ok(entry)
+    }
+
+    /// find the object that serves key (an object id or an alias)
+    fn get(&self, key: &str) -> Option<&Entry> {
+        let key = self.aliases.get(key).map(String::as_str).unwrap_or(key);
+        self.objects.get(key)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut Entry> {
+        let key = self.aliases.get(key).map(String::as_str).unwrap_or(key);
+        self.objects.get_mut(key)
+    }
+
+    /// set the scheduling weight of the object with id
+    fn weight(&mut self, id: &ObjectID, weight: usize) -> Result<()> {
+        assert!(weight >= 1, "weight must be at least 1");
+        let key = id.to_string();
+        let entry = self
+            .objects
+            .get_mut(&key)
+            .ok_or(Error::UnknownObject(key))?;
+        entry.weight = weight as i64;
+
+        Ok(())
+    }
+
+    /// serve the object with id only on the leader
+    fn leader(&mut self, id: &ObjectID) -> Result<()> {
+        let key = id.to_string();
+        let entry = self
+            .objects
+            .get_mut(&key)
+            .ok_or(Error::UnknownObject(key))?;
+        entry.leader = true;
+
+        Ok(())
+    }
+
+    /// the request queues of all objects (and their aliases) that can take one more call.
+    ///
+    /// BLPOP serves the first non empty queue, so the queues of all objects are ordered
+    /// by priority first. Then by the credit of their objects, the object that got the
+    /// least calls relative to its weight comes first (smooth weighted round robin, see
+    /// `served`). The objects that only run on the leader are skipped if this replica
+    /// is not the leader.
+    fn queues(&self, module: &str) -> Vec<String> {
+        let mut objects: Vec<(&String, &Entry)> = self
+            .objects
+            .iter()
+            .filter(|(_, entry)| entry.limits.available())
+            .filter(|(_, entry)| self.leading || !entry.leader)
+            .collect();
+        objects.sort_by(|(a, x), (b, y)| y.credit.cmp(&x.credit).then_with(|| a.cmp(b)));
+
+        let mut keys = vec![];
+        for (key, _) in objects {
+            keys.push(key);
+            keys.extend(
+                self.aliases
+                    .iter()
+                    .filter(|(_, object)| *object == key)
+                    .map(|(alias, _)| alias),
+            );
+        }
+
+        Priority::ALL
+            .iter()
+            .flat_map(|priority| keys.iter().map(move |key| priority.queue(module, key)))
+            .collect()
+    }
+
+    /// account a call to the object that serves key. All objects earn their weight
+    /// in credit, and the served object pays the total weight. An idle object can't
+    /// save more than the total weight, so it can't starve the others once it's busy.
+    fn served(&mut self, key: &str) {
+        let key = self.aliases.get(key).map(String::as_str).unwrap_or(key);
+        let total: i64 = self.objects.values().map(|entry| entry.weight).sum();
+        for entry in self.objects.values_mut() {
+            entry.credit = (entry.credit + entry.weight).min(total);
+        }
+
+        if let Some(entry) = self.objects.get_mut(key) {
+            entry.credit -= total;
+        }
+    }
+
+    fn info(&self) -> Vec<ObjectInfo> {
+        self.objects
+            .values()
+            .map(|entry| ObjectInfo {
+                id: entry.object.id(),
+                aliases: entry.versions.clone(),
+                streams: entry
+                    .streams
+                    .iter()
+                    .chain(entry.term.iter().flat_map(|term| term.streams.iter()))
+                    .map(|(name, status)| StreamInfo {
+                        name: name.clone(),
+                        status: status.lock().map(|s| s.clone()).unwrap_or_default(),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+/// a registry that is shared between the server and its workers
+#[derive(Clone, Default)]
+struct Shared(Arc<RwLock<Registry>>);
+
+impl Shared {
+    fn read(&self) -> RwLockReadGuard<'_, Registry> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Registry> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// start the streams of a registered object. The streams that only run on the
+/// leader are started by `start_term`.
+fn start_streams(
+    pool: &Pool<RedisConnectionManager>,
+    replica: &Replica,
+    module: &str,
+    key: &str,
+    entry: &mut Entry,
+) {
+    if entry.leader {
+        return;
+    }
+
+    match entry.object.streams() {
+        Ok(streams) => {
+            entry.streams = spawn_streams(pool, replica, module, key, streams, &entry.closed);
+        }
+        Err(err) => {
+            log::error!("error getting object streams: {}", err);
+        }
+    }
+}
+
+/// start the streams of a registered object that only run on the leader, it's called
+/// once this replica becomes the leader. The streams stop once the term is dropped
+/// (the leadership is lost) or the object is unregistered.
+fn start_term(
+    pool: &Pool<RedisConnectionManager>,
+    replica: &Replica,
+    module: &str,
+    key: &str,
+    entry: &mut Entry,
+) {
+    let mut streams = match entry.object.leader_streams() {
+        Ok(streams) => streams,
+        Err(err) => {
+            log::error!("error getting object leader streams: {}", err);
+            HashMap::default()
+        }
+    };
+
+    if entry.leader {
+        match entry.object.streams() {
+            Ok(all) => streams.extend(all),
+            Err(err) => log::error!("error getting object streams: {}", err),
+        }
+    }
+
+    let (alive, closed) = watch::channel(());
+    entry.term = Some(Term {
+        streams: spawn_streams(pool, replica, module, key, streams, &closed),
+        _alive: alive,
+    });
+}
+
+fn spawn_streams(
+    pool: &Pool<RedisConnectionManager>,
+    replica: &Replica,
+    module: &str,
+    key: &str,
+    streams: HashMap<String, Sink>,
+    closed: &watch::Receiver<()>,
+) -> StreamsStatus {
+    let mut status = vec![];
+    for (name, stream) in streams {
+        let fqdn = format!("{}.{}.{}", module, key, name);
+        log::debug!("starting stream: {}", fqdn);
+        status.push((name, stream.status()));
+        stream_worker(pool.clone(), replica.clone(), fqdn, stream, closed.clone());
+    }
+
+    status
+}
+
+/// Server module. Each module can has multiple registered objects.
 ///
 /// Number of workers specifies how many function calls a server
 /// can make at the same time. This can be set to one for workloads
 /// that need exclusive access to a certain resource.
+///
+/// Many servers (replicas) can run for the same module to scale it out, each request
+/// is served by one of them (see [`Server::with_replica`]). One of the replicas is
+/// the leader, objects and streams that must run once per module only run on the
+/// leader (see [`Server::leader`]).
 pub struct Server {
     module: String,
     pool: Pool<RedisConnectionManager>,
     workers: usize,
-    objects: Objects,
+    registry: Registry,
+    replica: String,
 }
 
 impl Server {
@@ -36,10 +379,23 @@ impl Server {
             pool,
             workers,
             module: module.as_ref().into(),
-            objects: Objects::new(),
+            registry: Registry::default(),
+            replica: Uuid::new_v4().to_string(),
         })
     }
 
+    /// set the id of this replica of the module, a random id is used by default.
+    ///
+    /// Replicas of a module pull requests from the same queues. A replica keeps the
+    /// requests it's running until they are answered, if the replica is gone they
+    /// are served by another replica (or by the same replica once it starts again
+    /// with the same id). So a request can be served more than once. Streams are
+    /// published by one replica at a time.
+    pub fn with_replica<S: Into<String>>(mut self, id: S) -> Self {
+        self.replica = id.into();
+        self
+    }
+
     /// register an object on this module. once
     /// registered, calls designated to this object
     /// will be dispatched to the object dispatch method
@@ -51,112 +407,390 @@ impl Server {
     /// implements the required functionality. Or better
     /// use the `object` macro to generate dispatcher and client
     /// stubs for that given interface.
-    pub fn register<T>(&mut self, object: T)
+    ///
+    /// fails if an object with the same id is already registered.
+    pub fn register<T>(&mut self, object: T) -> Result<()>
     where
         T: Object + Send + Sync + 'static,
     {
-        self.objects
-            .insert(object.id().to_string(), Box::new(object));
-    }
-
-    /// start the server. blocks forever. you can spawn it as a separate
-    /// task to avoid blocking of the main thread.
-    pub async fn run(self) {
-        for (key, object) in &self.objects {
-            match object.streams() {
-                Ok(streams) => {
-                    for (name, stream) in streams {
-                        let fqdn = format!("{}.{}.{}", self.module, key, name);
-                        log::debug!("starting stream: {}", fqdn);
-                        stream_worker(self.pool.clone(), fqdn, stream);
-                    }
-                }
-                Err(err) => {
-                    log::error!("error getting object streams: {}", err);
-                    continue;
-                }
-            }
+        let concurrency = object.concurrency();
+        self.register_with(object, concurrency)
+    }
+
+    /// register an object with concurrency limits, the limits declared by
+    /// the object are ignored.
+    pub fn register_with<T>(&mut self, object: T, concurrency: Concurrency) -> Result<()>
+    where
+        T: Object + Send + Sync + 'static,
+    {
+        self.registry.register(Arc::new(object), concurrency)
+    }
+
+    /// register a named instance of an object. A module can serve multiple instances
+    /// of the same object (for example one object per managed disk), each instance is
+    /// addressed as `name@version/instance`. Use `with_instance` on the stub to call
+    /// a specific instance.
+    ///
+    /// fails if the instance is already registered.
+    pub fn register_instance<T, S>(&mut self, object: T, instance: S) -> Result<()>
+    where
+        T: Object + Send + Sync + 'static,
+        S: Into<String>,
+    {
+        let id = object.id().with_instance(instance);
+        self.register(Instance { id, object })
+    }
+
+    /// serve calls to another version of the registered object with id. This is
+    /// used when an object is upgraded (say from 1.0 to 1.1) so clients built against
+    /// an older compatible version can still reach it. The version must be compatible
+    /// with the object version (same major, and not newer).
+    ///
+    /// Aliases are listed in the module info, so clients can discover them. Note that
+    /// streams are only published under the object version.
+    pub fn alias<S>(&mut self, id: &ObjectID, version: S) -> Result<()>
+    where
+        S: AsRef<str>,
+    {
+        self.registry.alias(id, version.as_ref())
+    }
+
+    /// set the scheduling weight of the registered object with id (1 by default).
+    /// When several objects have pending requests, calls are pulled from each object
+    /// in proportion to its weight. For example an object with weight 2 gets twice
+    /// the calls of an object with weight 1.
+    pub fn weight(&mut self, id: &ObjectID, weight: usize) -> Result<()> {
+        self.registry.weight(id, weight)
+    }
+
+    /// serve the registered object with id only on the leader replica of the module,
+    /// its calls are served and its streams are published by the leader. This is
+    /// the same as declaring the object with `#[object(leader)]`.
+    ///
+    /// The replicas elect the leader with a lease that the leader renews every few
+    /// seconds. Once the leader is gone another replica takes over within the lease
+    /// time (15 seconds). A leader that fails to renew its lease stops the leader
+    /// streams and objects, so only one replica runs them as long as it can reach redis.
+    pub fn leader(&mut self, id: &ObjectID) -> Result<()> {
+        self.registry.leader(id)
+    }
+
+    /// start the server in the background. Must be called from within a tokio runtime.
+    ///
+    /// The returned handle can be used to register and unregister objects while
+    /// the server is running. The handle is also a future that never completes, so
+    /// awaiting it blocks forever.
+    pub fn run(mut self) -> ServerHandle {
+        let replica = Replica::new(self.pool.clone(), self.module.clone(), self.replica);
+        for (key, entry) in self.registry.objects.iter_mut() {
+            start_streams(&self.pool, &replica, &self.module, key, entry);
         }
 
         log::debug!("streams started successfully");
-        // routers can not be changed afterwords. so we need to spawn workers here
-        // and pass them a copy of the routers, and a way for them to pull for messages.
-        let module = self.module;
-        let routers = self.objects;
-        let queues: Vec<String> = routers
-            .keys()
-            .map(|k| format!("{}.{}", module, k))
-            .collect();
+        let registry = Shared(Arc::new(RwLock::new(self.registry)));
+        heartbeat(
+            self.pool.clone(),
+            replica.clone(),
+            self.module.clone(),
+            registry.clone(),
+        );
+        election(
+            self.pool.clone(),
+            replica.clone(),
+            self.module.clone(),
+            registry.clone(),
+        );
 
-        log::debug!("pulling from: {:?}", queues);
-        let worker = Worker::new(self.pool.clone(), routers);
-        let mut workers = workers::WorkerPool::new(worker, self.workers);
+        let worker = Worker::new(
+            self.pool.clone(),
+            replica.clone(),
+            self.module.clone(),
+            registry.clone(),
+        );
+        let task = tokio::spawn(serve(
+            replica.clone(),
+            self.module.clone(),
+            registry.clone(),
+            workers::WorkerPool::new(worker, self.workers),
+        ));
+
+        ServerHandle {
+            module: self.module,
+            pool: self.pool,
+            replica,
+            registry,
+            task,
+        }
+    }
+}
+
+/// pull requests of the registered objects and hand them to the workers
+async fn serve(
+    replica: Replica,
+    module: String,
+    registry: Shared,
+    mut workers: workers::WorkerPool<Worker>,
+) {
+    // requests that were not answered by a previous run of this replica
+    if let Err(err) = replica.requeue(replica.id()).await {
+        log::error!("failed to requeue pending requests: {}", err);
+    }
+
+    loop {
+        let worker = workers.get().await;
 
         loop {
-            let worker = workers.get().await;
-
-            loop {
-                let mut con = match self.pool.get().await {
-                    Ok(con) => con,
-                    Err(err) => {
-                        log::error!("failed to get redis connection: {}", err);
-                        sleep(Duration::from_secs(2)).await;
-                        continue;
-                    }
-                };
+            // objects can be registered while the server is running, and objects that
+            // reached their concurrency limit are skipped. so the queues are listed
+            // again on each pull.
+            let queues = registry.read().queues(&module);
+
+            // the request is kept until it's answered, so it can be recovered if this
+            // replica is gone. the server only blocks on the queues once they are empty
+            let pulled = match replica.pull(&queues).await {
+                Ok(None) => replica.wait(&queues, PULL_TIMEOUT).await,
+                pulled => pulled,
+            };
 
-                let (_, request): (String, Request) = match con.blpop(&queues, PULL_TIMEOUT).await {
-                    Err(err) => {
-                        log::error!("failed to get get request: {}", err);
-                        sleep(Duration::from_secs(2)).await;
-                        continue;
+            let (queue, value) = match pulled {
+                Err(err) => {
+                    log::error!("failed to get request: {}", err);
+                    sleep(Duration::from_secs(2)).await;
+                    continue;
+                }
+                Ok(Some(pulled)) => pulled,
+                // a worker wakes the server up once a skipped object can take calls again
+                Ok(None) => continue,
+            };
+
+            let (request, raw) = match (Request::from_redis_value(&value), value) {
+                (Ok(request), redis::Value::Data(raw)) => (request, raw),
+                (Err(err), value) => {
+                    log::error!("failed to decode request: {}", err);
+                    if let redis::Value::Data(raw) = value {
+                        if let Err(err) = replica.done(&raw).await {
+                            log::error!("failed to clear invalid request: {}", err);
+                        }
                     }
-                    Ok(Some(value)) => value,
-                    Ok(None) => continue,
-                };
+                    continue;
+                }
+                // a request is always decoded from data
+                (Ok(_), _) => continue,
+            };
 
-                if let Err(err) = worker.send(request) {
-                    log::error!("failed to schedule request: {}", err);
+            let permit = {
+                let key = request.object.to_string();
+                let mut registry = registry.write();
+                match registry.get_mut(&key) {
+                    Some(entry) => entry.limits.try_acquire(&request.method),
+                    None => Some(Permit::default()),
                 }
+            };
+
+            // the object or the method is running its max number of calls. the request
+            // is put back in front of its queue, and the object is skipped until it can
+            // take the call (a worker wakes the server up)
+            let permit = match permit {
+                Some(permit) => permit,
+                None => {
+                    if let Err(err) = replica.unpull(&queue, &raw).await {
+                        log::error!("failed to requeue request: {}", err);
+                    }
+                    continue;
+                }
+            };
+
+            registry.write().served(&request.object.to_string());
+
+            if let Err(err) = worker.send(Job {
+                request,
+                raw,
+                permit,
+            }) {
+                log::error!("failed to schedule request: {}", err);
+            }
+
+            break;
+        }
+    }
+}
+
+/// ServerHandle is returned by [`Server::run`]. It's used to add and remove objects
+/// while the server is running, for example when a new device appears.
+///
+/// Calls to a newly registered object are picked up within a few seconds. Once an
+/// object is unregistered its streams are stopped, calls that are still running
+/// complete, but requests that are not picked up yet are left unanswered.
+pub struct ServerHandle {
+    module: String,
+    pool: Pool<RedisConnectionManager>,
+    replica: Replica,
+    registry: Shared,
+    task: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    /// register an object on the running server and start its streams. see
+    /// [`Server::register`]
+    pub fn register<T>(&self, object: T) -> Result<()>
+    where
+        T: Object + Send + Sync + 'static,
+    {
+        let concurrency = object.concurrency();
+        self.register_with(object, concurrency)
+    }
 
-                break;
+    /// register an object with concurrency limits on the running server. see
+    /// [`Server::register_with`]
+    pub fn register_with<T>(&self, object: T, concurrency: Concurrency) -> Result<()>
+    where
+        T: Object + Send + Sync + 'static,
+    {
+        let key = object.id().to_string();
+        let mut registry = self.registry.write();
+        registry.register(Arc::new(object), concurrency)?;
+        let leading = registry.leading;
+        if let Some(entry) = registry.objects.get_mut(&key) {
+            start_streams(&self.pool, &self.replica, &self.module, &key, entry);
+            if leading {
+                start_term(&self.pool, &self.replica, &self.module, &key, entry);
             }
         }
+
+        Ok(())
+    }
+
+    /// register a named instance of an object on the running server. see
+    /// [`Server::register_instance`]
+    pub fn register_instance<T, S>(&self, object: T, instance: S) -> Result<()>
+    where
+        T: Object + Send + Sync + 'static,
+        S: Into<String>,
+    {
+        let id = object.id().with_instance(instance);
+        self.register(Instance { id, object })
     }
+
+    /// serve calls to another version of a registered object. see [`Server::alias`]
+    pub fn alias<S>(&self, id: &ObjectID, version: S) -> Result<()>
+    where
+        S: AsRef<str>,
+    {
+        self.registry.write().alias(id, version.as_ref())
+    }
+
+    /// set the scheduling weight of a registered object. see [`Server::weight`]
+    pub fn weight(&self, id: &ObjectID, weight: usize) -> Result<()> {
+        self.registry.write().weight(id, weight)
+    }
+
+    /// remove the object with id (and its aliases) from the running server, and
+    /// stop its streams.
+    pub fn unregister(&self, id: &ObjectID) -> Result<()> {
+        // the streams of the object stop once the entry is dropped
+        self.registry.write().unregister(id).map(|_| ())
+    }
+
+    /// true if this replica is the leader of the module. see [`Server::leader`]
+    pub fn is_leader(&self) -> bool {
+        self.registry.read().leading
+    }
+
+    /// ids of the registered objects
+    pub fn objects(&self) -> Vec<ObjectID> {
+        self.registry
+            .read()
+            .objects
+            .values()
+            .map(|entry| entry.object.id())
+            .collect()
+    }
+}
+
+impl Future for ServerHandle {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.task).poll(cx).map(|_| ())
+    }
+}
+
+/// a pulled request (and its encoded form), and the call slots of its object
+/// and method
+struct Job {
+    request: Request,
+    raw: Vec<u8>,
+    permit: Permit,
 }
 
 #[derive(Clone)]
 struct Worker {
-    routers: Arc<Objects>,
+    module: String,
+    registry: Shared,
     pool: Pool<RedisConnectionManager>,
+    replica: Replica,
+    // channels of the running parameterized streams
+    subscriptions: Arc<Mutex<HashSet<String>>>,
 }
 
 impl Worker {
-    fn new(pool: Pool<RedisConnectionManager>, routers: Objects) -> Self {
+    fn new(
+        pool: Pool<RedisConnectionManager>,
+        replica: Replica,
+        module: String,
+        registry: Shared,
+    ) -> Self {
         Self {
             pool,
-            routers: Arc::new(routers),
+            replica,
+            module,
+            registry,
+            subscriptions: Arc::new(Mutex::new(HashSet::new())),
         }
     }
 
-    async fn respond<S: Into<String>>(&self, id: S, ret: Result<Output>) -> anyhow::Result<()> {
-        use anyhow::Context;
+    /// subscribe to a parameterized stream. the stream producer is started if this is
+    /// the first subscription to this set of arguments. returns the channel name where
+    /// the stream events are published.
+    async fn subscribe(
+        &self,
+        object: &(dyn Object + Send + Sync),
+        closed: watch::Receiver<()>,
+        request: Request,
+    ) -> Result<Output> {
+        let channel = format!(
+            "{}.{}.{}.{:016x}",
+            self.module,
+            object.id(),
+            request.method,
+            request.inputs.fingerprint()?
+        );
 
-        let id = id.into();
+        let mut subscriptions = self.subscriptions.lock().await;
+        // another replica may already publish this stream
+        if !subscriptions.contains(&channel) && self.replica.claim(&channel).await {
+            let sink = object.stream(&request.method, &request.inputs)?;
+            // the client is subscribing right now
+            sink.set_observers(1);
+            log::debug!("starting stream: {}", channel);
+            subscriptions.insert(channel.clone());
+            subscription_worker(
+                self.pool.clone(),
+                self.replica.clone(),
+                channel.clone(),
+                sink,
+                closed,
+                Arc::clone(&self.subscriptions),
+            );
+        }
 
-        let response = match ret {
-            Ok(output) => Response {
-                id: id.clone(),
-                output,
-                error: None,
-            },
-            Err(err) => Response {
-                id: id.clone(),
-                output: Output::default(),
-                error: Some(err.to_string()),
-            },
-        };
+        Ok(Output::with_codec(request.codec(), Ok::<_, Error>(channel)))
+    }
 
+    async fn respond(&self, response: Response) -> anyhow::Result<()> {
+        use anyhow::Context;
+
+        let id = response.id.clone();
         let mut con = self
             .pool
             .get()
@@ -173,40 +807,451 @@ impl Worker {
 
 #[async_trait::async_trait]
 impl workers::Work for Worker {
-    type Input = Request;
+    type Input = Job;
     type Output = ();
 
-    async fn run(&self, input: Self::Input) -> Self::Output {
+    async fn run(&self, job: Self::Input) -> Self::Output {
+        let Job {
+            request,
+            raw,
+            permit,
+        } = job;
+        self.call(request).await;
+
+        if let Err(err) = self.replica.done(&raw).await {
+            log::error!("failed to clear answered request: {}", err);
+        }
+
+        // pull again the requests of the object that was running its max number of calls
+        if permit.release() {
+            if let Err(err) = self.replica.wake().await {
+                log::error!("failed to wake up server: {}", err);
+            }
+        }
+    }
+}
+
+impl Worker {
+    async fn call(&self, mut input: Request) {
         // dispatch message to handlers.
         let id = input.id.clone();
+        let codec = input.codec();
+        let compression = input.compression.as_ref().map(|c| c.threshold);
+        let chunking = input.chunking.as_ref().map(|c| c.size);
         let object = input.object.to_string();
-        let response = match self.routers.get(&object) {
-            Some(service) => service.dispatch(input).await,
-            None => Err(Error::UnknownObject(object.clone())),
+
+        // the retries of an idempotent request are answered with the response of its first run
+        let key = input.idempotency_key.clone();
+        if let Some(key) = &key {
+            match dedup::begin(&self.pool, &self.module, self.replica.id(), key).await {
+                Ok(Seen::New) => {}
+                Ok(Seen::Running) => {
+                    log::debug!("request '{}' is already running", id);
+                    return;
+                }
+                Ok(Seen::Done(response)) => {
+                    log::debug!("request '{}' already ran", id);
+                    if let Err(err) = self.respond(Response { id, ..response }).await {
+                        log::error!("failed to send response: {}", err);
+                    }
+                    return;
+                }
+                Err(err) => log::error!("failed to deduplicate request '{}': {}", id, err),
+            }
+        }
+
+        let service = self
+            .registry
+            .read()
+            .get(&object)
+            .map(|entry| (Arc::clone(&entry.object), entry.closed.clone()));
+
+        let mut response = match chunked::join_request(&self.pool, &mut input).await {
+            Err(err) => Err(err),
+            Ok(_) => match service {
+                Some((service, closed)) if input.kind == Kind::Subscribe => {
+                    self.subscribe(service.as_ref(), closed, input).await
+                }
+                Some((service, _)) => {
+                    chunked::scope(self.pool.clone(), service.dispatch(input)).await
+                }
+                None => Err(Error::UnknownObject(object.clone())),
+            },
         };
 
-        if let Err(err) = self.respond(id, response).await {
+        // outputs are transcoded before they are compressed or split
+        if let Ok(output) = response.as_mut() {
+            if let Err(err) = output.transcode(codec) {
+                response = Err(err);
+            }
+        }
+
+        if let (Ok(output), Some(threshold)) = (response.as_mut(), compression) {
+            output.compress(threshold);
+        }
+
+        if let (Ok(output), Some(size)) = (response.as_mut(), chunking) {
+            if let Err(err) = chunked::split_output(&self.pool, output, size).await {
+                response = Err(err);
+            }
+        }
+
+        let response = Response::new(id, codec, response);
+        if let Some(key) = &key {
+            if let Err(err) =
+                dedup::finish(&self.pool, &self.module, self.replica.id(), key, &response).await
+            {
+                log::error!("failed to store response: {}", err);
+            }
+        }
+
+        if let Err(err) = self.respond(response).await {
             log::error!("failed to send response: {}", err);
         }
     }
 }
 
-fn stream_worker(pool: Pool<RedisConnectionManager>, channel: String, mut receiver: Sink) {
+fn stream_worker(
+    pool: Pool<RedisConnectionManager>,
+    replica: Replica,
+    channel: String,
+    mut receiver: Sink,
+    mut closed: watch::Receiver<()>,
+) {
     tokio::spawn(async move {
-        while let Some(msg) = receiver.recv().await {
-            let mut con = match pool.get().await {
-                Ok(con) => con,
-                Err(err) => {
-                    log::error!("failed to get connection: {}", err);
-                    continue;
+        let mut observer = tokio::time::interval(OBSERVE_INTERVAL);
+        // only the replica that owns the stream publishes its events, the
+        // others drop them until they take over the stream.
+        let mut owner = replica.claim(&channel).await;
+        loop {
+            tokio::select! {
+                msg = receiver.recv() => {
+                    let msg = match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    };
+
+                    if owner {
+                        publish(&pool, &channel, msg).await;
+                    }
                 }
-            };
-            let _: () = match con.publish(&channel, msg.into_vec()).await {
-                Ok(x) => x,
-                Err(err) => {
-                    log::error!("failed to publish event: {}", err);
+                _ = observer.tick() => {
+                    owner = replica.claim(&channel).await;
+                    match subscribers(&pool, &channel).await {
+                        Ok(count) if owner => receiver.set_observers(count),
+                        Ok(_) => receiver.set_observers(0),
+                        Err(err) => log::error!("failed to get stream subscribers: {}", err),
+                    }
                 }
+                _ = closed.changed() => {
+                    // the object was unregistered
+                    log::debug!("stopping stream: {}", channel);
+                    replica.release(&channel).await;
+                    return;
+                }
+            }
+        }
+
+        replica.release(&channel).await;
+        // all senders are gone, the stream is not supervised or the
+        // supervisor gave up.
+        log::error!("stream '{}' stopped", channel);
+        if let Ok(mut status) = receiver.status().lock() {
+            if status.health == Health::Running {
+                status.health = Health::Stopped;
+            }
+        }
+    });
+}
+
+/// periodically publish the module info, including the status of the streams. The
+/// info is published for the module and for this replica, then the requests of the
+/// replicas that are gone are recovered.
+fn heartbeat(
+    pool: Pool<RedisConnectionManager>,
+    replica: Replica,
+    module: String,
+    registry: Shared,
+) {
+    tokio::spawn(async move {
+        let key = introspect::key(&module);
+        let replica_key = introspect::replica_key(&module, replica.id());
+        loop {
+            let objects = registry.read().info();
+            let info = ModuleInfo {
+                replica: Some(replica.id().into()),
+                ..ModuleInfo::new(&module, objects)
             };
+            match (protocol::encode(&info), pool.get().await) {
+                (Ok(data), Ok(mut con)) => {
+                    let data = data.into_vec();
+                    for key in [&key, &replica_key] {
+                        if let Err(err) = con
+                            .set_ex::<_, _, ()>(key, &data, introspect::INFO_TTL)
+                            .await
+                        {
+                            log::error!("failed to publish module info: {}", err);
+                        }
+                    }
+
+                    if let Err(err) = replica.alive().await {
+                        log::error!("failed to register replica: {}", err);
+                    }
+
+                    if let Err(err) = replica.recover().await {
+                        log::error!("failed to recover requests: {}", err);
+                    }
+                }
+                (Err(err), _) => log::error!("failed to encode module info: {}", err),
+                (_, Err(err)) => log::error!("failed to get connection: {}", err),
+            }
+
+            sleep(introspect::INFO_INTERVAL).await;
+        }
+    });
+}
+
+/// periodically take (or renew) the leader lease of the module. Once this replica
+/// becomes the leader it starts the leader streams and pulls the requests of the leader
+/// objects, it stops them once it loses the lease.
+fn election(
+    pool: Pool<RedisConnectionManager>,
+    replica: Replica,
+    module: String,
+    registry: Shared,
+) {
+    tokio::spawn(async move {
+        let mut renew = tokio::time::interval(LEADER_RENEW);
+        loop {
+            renew.tick().await;
+            let leading = replica.lead().await;
+            {
+                let mut registry = registry.write();
+                if leading == registry.leading {
+                    continue;
+                }
+
+                registry.leading = leading;
+                for (key, entry) in registry.objects.iter_mut() {
+                    if leading {
+                        start_term(&pool, &replica, &module, key, entry);
+                    } else {
+                        entry.term = None;
+                    }
+                }
+            }
+
+            if leading {
+                log::info!("replica '{}' is the leader of '{}'", replica.id(), module);
+                // the queues of the leader objects are pulled from now on
+                if let Err(err) = replica.wake().await {
+                    log::error!("failed to wake up server: {}", err);
+                }
+            } else {
+                log::warn!(
+                    "replica '{}' lost the leadership of '{}'",
+                    replica.id(),
+                    module
+                );
+            }
+        }
+    });
+}
+
+/// publish event on channel. publishing is retried until it succeeds, meanwhile
+/// the stream buffers (or drops) new events according to its overflow policy.
+async fn publish(pool: &Pool<RedisConnectionManager>, channel: &str, msg: ByteBuf) {
+    let msg = msg.into_vec();
+    loop {
+        let mut con = match pool.get().await {
+            Ok(con) => con,
+            Err(err) => {
+                log::error!("failed to get connection: {}", err);
+                sleep(PUBLISH_RETRY).await;
+                continue;
+            }
+        };
+
+        match con.publish::<_, _, ()>(channel, &msg).await {
+            Ok(_) => return,
+            Err(err) => {
+                log::error!("failed to publish event: {}", err);
+                sleep(PUBLISH_RETRY).await;
+            }
         }
+    }
+}
+
+/// number of clients subscribed to channel
+async fn subscribers(pool: &Pool<RedisConnectionManager>, channel: &str) -> anyhow::Result<usize> {
+    let mut con = pool.get().await?;
+    let (_, count): (String, usize) = redis::cmd("PUBSUB")
+        .arg("NUMSUB")
+        .arg(channel)
+        .query_async(&mut *con)
+        .await?;
+
+    Ok(count)
+}
+
+/// publishes the events of a parameterized stream until there are no more subscribers
+/// to the channel, then drops the sink which stops the stream producer.
+fn subscription_worker(
+    pool: Pool<RedisConnectionManager>,
+    replica: Replica,
+    channel: String,
+    mut receiver: Sink,
+    mut closed: watch::Receiver<()>,
+    subscriptions: Arc<Mutex<HashSet<String>>>,
+) {
+    tokio::spawn(async move {
+        let mut reaper = tokio::time::interval(OBSERVE_INTERVAL);
+        // first tick completes immediately
+        reaper.tick().await;
+        let mut idle = 0;
+        loop {
+            tokio::select! {
+                msg = receiver.recv() => {
+                    let msg = match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    };
+
+                    publish(&pool, &channel, msg).await;
+                }
+                _ = reaper.tick() => {
+                    // keep the ownership of the stream
+                    replica.claim(&channel).await;
+                    match subscribers(&pool, &channel).await {
+                        Ok(0) => {
+                            receiver.set_observers(0);
+                            idle += 1;
+                        }
+                        Ok(count) => {
+                            receiver.set_observers(count);
+                            idle = 0;
+                        }
+                        Err(err) => log::error!("failed to get stream subscribers: {}", err),
+                    }
+
+                    if idle >= REAP_IDLE {
+                        break;
+                    }
+                }
+                _ = closed.changed() => break,
+            }
+        }
+
+        log::debug!("stopping stream: {}", channel);
+        subscriptions.lock().await.remove(&channel);
+        replica.release(&channel).await;
     });
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::SimpleObject;
+
+    #[test]
+    fn registry() {
+        let mut registry = Registry::default();
+        let id = ObjectID::new("calculator", "1.2");
+        let object = || Arc::new(SimpleObject::new(id.clone()));
+        registry.register(object(), Concurrency::new(1)).unwrap();
+        assert!(matches!(
+            registry.register(object(), Concurrency::unlimited()),
+            Err(Error::AlreadyRegistered(_))
+        ));
+
+        registry.alias(&id, "1.0").unwrap();
+        assert!(registry.get("calculator@1.0").is_some());
+        // the queues of higher priorities come first
+        assert_eq!(
+            registry.queues("test"),
+            [
+                "test.calculator@1.2:high",
+                "test.calculator@1.0:high",
+                "test.calculator@1.2",
+                "test.calculator@1.0",
+                "test.calculator@1.2:low",
+                "test.calculator@1.0:low",
+            ]
+        );
+
+        // the object (and its alias) are not pulled while it's running a call
+        let permit = registry
+            .get_mut("calculator@1.2")
+            .unwrap()
+            .limits
+            .try_acquire("add");
+        assert!(registry.queues("test").is_empty());
+        drop(permit);
+        assert_eq!(registry.queues("test").len(), 6);
+
+        registry.unregister(&id).unwrap();
+        assert!(registry.get("calculator@1.0").is_none());
+        assert!(registry.queues("test").is_empty());
+        assert!(registry.unregister(&id).is_err());
+    }
+
+    #[test]
+    fn leader() {
+        let mut registry = Registry::default();
+        for name in ["a", "b"] {
+            let object = SimpleObject::new(ObjectID::new(name, "1.0"));
+            registry
+                .register(Arc::new(object), Concurrency::unlimited())
+                .unwrap();
+        }
+
+        registry.leader(&ObjectID::new("b", "1.0")).unwrap();
+        assert!(registry.leader(&ObjectID::new("c", "1.0")).is_err());
+
+        // the leader object is only pulled by the leader
+        assert_eq!(
+            registry.queues("test"),
+            ["test.a@1.0:high", "test.a@1.0", "test.a@1.0:low"]
+        );
+        registry.leading = true;
+        assert_eq!(registry.queues("test").len(), 6);
+    }
+
+    #[test]
+    fn fair() {
+        let mut registry = Registry::default();
+        for name in ["a", "b", "c"] {
+            let object = SimpleObject::new(ObjectID::new(name, "1.0"));
+            registry
+                .register(Arc::new(object), Concurrency::unlimited())
+                .unwrap();
+        }
+
+        // all queues are busy, each pull is served by the first queue
+        let pull = |registry: &mut Registry| {
+            let queue = registry.queues("test").remove(0);
+            let key = queue
+                .trim_start_matches("test.")
+                .trim_end_matches(":high")
+                .to_string();
+            registry.served(&key);
+            key
+        };
+
+        // equal weights rotate the queues
+        let order: Vec<String> = (0..6).map(|_| pull(&mut registry)).collect();
+        assert_eq!(
+            order,
+            ["a@1.0", "b@1.0", "c@1.0", "a@1.0", "b@1.0", "c@1.0"]
+        );
+
+        registry.weight(&ObjectID::new("c", "1.0"), 2).unwrap();
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for _ in 0..40 {
+            *counts.entry(pull(&mut registry)).or_default() += 1;
+        }
+        assert_eq!(counts["a@1.0"], 10);
+        assert_eq!(counts["b@1.0"], 10);
+        assert_eq!(counts["c@1.0"], 20);
+    }
+}
@@ -0,0 +1,302 @@
+//! Replicas of a module.
+//!
+//! Many servers (replicas) can serve the same module, they pull requests from the
+//! same queues so each request is served by one replica. Each replica:
+//! - publishes its own info and is listed in the module replicas (see [`crate::introspect`])
+//! - keeps the requests it's running in `{module}.$pending.{replica}` until they are
+//!   answered. Once a replica is gone (its info expired) another replica pushes its
+//!   pending requests back to their queues, so a request is served at least once
+//! - publishes a stream only while it owns the stream (`{channel}.$owner`), so
+//!   subscribers don't get the same event from every replica. Another replica takes
+//!   over the stream if the owner is gone
+//!
+//! One of the replicas is the leader of the module, it holds a lease (`{module}.$leader`)
+//! that it renews periodically. The objects and streams that must run once per module
+//! only run on the leader, once the lease expires (the leader is gone) another replica
+//! takes over.
+use super::dedup;
+use super::redis::RESPONSE_TTL;
+use crate::introspect;
+use crate::protocol::Request;
+use bb8_redis::{
+    bb8::Pool,
+    redis::{self, AsyncCommands, FromRedisValue},
+    RedisConnectionManager,
+};
+use std::time::Duration;
+
+/// the owner of a stream must claim it again within this time (in seconds). The stream
+/// workers claim their streams each time they check the stream subscribers.
+const OWNER_TTL: usize = 15;
+/// the leader must renew its lease within this time (in seconds)
+const LEADER_TTL: usize = 15;
+/// how often the leader renews its lease, and the other replicas try to take it
+pub(crate) const LEADER_RENEW: Duration = Duration::from_secs(5);
+
+/// set a key (KEYS[1]) to the replica id (ARGV[1]) if it's not set, or renew it if
+/// it's already set to the replica id, for a ttl (ARGV[2]). returns 1 if the key is
+/// held by the replica
+const ACQUIRE: &str = r#"
+if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'EX', ARGV[2]) then
+    return 1
+end
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('EXPIRE', KEYS[1], ARGV[2])
+    return 1
+end
+return 0
+"#;
+
+/// delete a key (KEYS[1]) if it's set to the replica id (ARGV[1])
+const RELEASE: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+/// pop the first request of the queues (all keys but the last, in order) and push it
+/// to the pending list (the last key)
+const PULL: &str = r#"
+local pending = KEYS[#KEYS]
+for i = 1, #KEYS - 1 do
+    local request = redis.call('LPOP', KEYS[i])
+    if request then
+        redis.call('RPUSH', pending, request)
+        return {KEYS[i], request}
+    end
+end
+return false
+"#;
+
+/// remove a request (ARGV[1]) from the pending list (KEYS[1]) and push it back in
+/// front of its queue (KEYS[2]), unless it was already requeued
+const UNPULL: &str = r#"
+if redis.call('LREM', KEYS[1], 1, ARGV[1]) == 1 then
+    redis.call('LPUSH', KEYS[2], ARGV[1])
+end
+"#;
+
+#[derive(Clone)]
+pub(crate) struct Replica {
+    module: String,
+    id: String,
+    pool: Pool<RedisConnectionManager>,
+}
+
+impl Replica {
+    pub(crate) fn new(pool: Pool<RedisConnectionManager>, module: String, id: String) -> Replica {
+        Replica { module, id, pool }
+    }
+
+    pub(crate) fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// key that wakes up the pull loop of the replica
+    fn wake_key(&self) -> String {
+        format!("{}.$wake.{}", self.module, self.id)
+    }
+
+    /// wake up the pull loop of the replica
+    pub(crate) async fn wake(&self) -> anyhow::Result<()> {
+        let wake = self.wake_key();
+        let mut con = self.pool.get().await?;
+        con.rpush::<_, _, ()>(&wake, 1).await?;
+        let _ = con.expire::<_, ()>(&wake, RESPONSE_TTL).await;
+        Ok(())
+    }
+
+    fn pending_key(&self, replica: &str) -> String {
+        format!("{}.$pending.{}", self.module, replica)
+    }
+
+    /// pop the first request of the queues (in order) and keep it until it's answered.
+    /// Both are done at once, so a request is not lost if the replica is gone right
+    /// after the pop. returns the queue and the request, None if the queues are empty.
+    pub(crate) async fn pull(
+        &self,
+        queues: &[String],
+    ) -> anyhow::Result<Option<(String, redis::Value)>> {
+        let mut con = self.pool.get().await?;
+        let pulled = redis::cmd("EVAL")
+            .arg(PULL)
+            .arg(queues.len() + 1)
+            .arg(queues)
+            .arg(self.pending_key(&self.id))
+            .query_async(&mut *con)
+            .await?;
+        Ok(pulled)
+    }
+
+    /// wait for a request in one of the queues (in order), at most timeout (in seconds).
+    /// The popped request is kept until it's answered like a pulled one. returns None on
+    /// timeout, or once the pull loop is woken up.
+    pub(crate) async fn wait(
+        &self,
+        queues: &[String],
+        timeout: usize,
+    ) -> anyhow::Result<Option<(String, redis::Value)>> {
+        let wake = self.wake_key();
+        let mut keys = queues.to_vec();
+        keys.push(wake.clone());
+
+        let mut con = self.pool.get().await?;
+        let popped: Option<(String, Vec<u8>)> = con.blpop(&keys, timeout).await?;
+        match popped {
+            Some((queue, request)) if queue != wake => {
+                con.rpush::<_, _, ()>(self.pending_key(&self.id), &request)
+                    .await?;
+                Ok(Some((queue, redis::Value::Data(request))))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// push a pulled request back in front of its queue
+    pub(crate) async fn unpull(&self, queue: &str, request: &[u8]) -> anyhow::Result<()> {
+        let mut con = self.pool.get().await?;
+        redis::cmd("EVAL")
+            .arg(UNPULL)
+            .arg(2)
+            .arg(self.pending_key(&self.id))
+            .arg(queue)
+            .arg(request)
+            .query_async::<_, ()>(&mut *con)
+            .await?;
+        Ok(())
+    }
+
+    /// the request was answered
+    pub(crate) async fn done(&self, request: &[u8]) -> anyhow::Result<()> {
+        let mut con = self.pool.get().await?;
+        con.lrem::<_, _, ()>(self.pending_key(&self.id), 1, request)
+            .await?;
+        Ok(())
+    }
+
+    /// register the replica as alive, it's called with each info update
+    pub(crate) async fn alive(&self) -> anyhow::Result<()> {
+        let mut con = self.pool.get().await?;
+        con.sadd::<_, _, ()>(introspect::replicas_key(&self.module), &self.id)
+            .await?;
+        Ok(())
+    }
+
+    /// push the pending requests of the replicas that are gone back to their queues
+    pub(crate) async fn recover(&self) -> anyhow::Result<()> {
+        let mut con = self.pool.get().await?;
+        let replicas: Vec<String> = con.smembers(introspect::replicas_key(&self.module)).await?;
+
+        for replica in replicas {
+            if replica == self.id {
+                continue;
+            }
+
+            let alive: bool = con
+                .exists(introspect::replica_key(&self.module, &replica))
+                .await?;
+            if alive {
+                continue;
+            }
+
+            log::warn!("replica '{}' of '{}' is gone", replica, self.module);
+            self.requeue(&replica).await?;
+            con.srem::<_, _, ()>(introspect::replicas_key(&self.module), &replica)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// push the pending requests of replica back to their queues. A replica also requeues
+    /// its own requests (of a previous run with the same id) once it starts.
+    pub(crate) async fn requeue(&self, replica: &str) -> anyhow::Result<()> {
+        let mut con = self.pool.get().await?;
+        let key = self.pending_key(replica);
+        loop {
+            // the newest request is pushed first, so the oldest ends at the head of its queue
+            let raw: Option<Vec<u8>> = con.rpop(&key, None).await?;
+            let raw = match raw {
+                Some(raw) => raw,
+                None => return Ok(()),
+            };
+
+            match Request::from_redis_value(&redis::Value::Data(raw.clone())) {
+                Ok(request) => {
+                    log::debug!("requeue request '{}'", request.id);
+                    // the request didn't complete, so it must run again
+                    if let Some(key) = &request.idempotency_key {
+                        dedup::abandon(&self.pool, &self.module, replica, key).await?;
+                    }
+                    con.lpush::<_, _, ()>(request.queue(&self.module), raw)
+                        .await?;
+                }
+                Err(err) => log::error!("dropping invalid pending request: {}", err),
+            }
+        }
+    }
+
+    /// claim (or keep) the ownership of a stream channel. returns true if this
+    /// replica owns the channel.
+    pub(crate) async fn claim(&self, channel: &str) -> bool {
+        match self.acquire(&owner_key(channel), OWNER_TTL).await {
+            Ok(owner) => owner,
+            Err(err) => {
+                log::error!("failed to claim stream '{}': {}", channel, err);
+                false
+            }
+        }
+    }
+
+    /// take (or renew) the leader lease of the module. returns true if this replica
+    /// is the leader. A replica that fails to renew its lease is not the leader anymore.
+    pub(crate) async fn lead(&self) -> bool {
+        match self
+            .acquire(&introspect::leader_key(&self.module), LEADER_TTL)
+            .await
+        {
+            Ok(leader) => leader,
+            Err(err) => {
+                log::error!(
+                    "failed to renew the leader lease of '{}': {}",
+                    self.module,
+                    err
+                );
+                false
+            }
+        }
+    }
+
+    /// set key to the replica id if it's not set (or already set to the replica id)
+    /// for ttl seconds. returns true if the key is held by this replica.
+    async fn acquire(&self, key: &str, ttl: usize) -> anyhow::Result<bool> {
+        let mut con = self.pool.get().await?;
+        let held: bool = redis::cmd("EVAL")
+            .arg(ACQUIRE)
+            .arg(1)
+            .arg(key)
+            .arg(&self.id)
+            .arg(ttl)
+            .query_async(&mut *con)
+            .await?;
+        Ok(held)
+    }
+
+    /// give up the ownership of a stream channel, so another replica can take it
+    pub(crate) async fn release(&self, channel: &str) {
+        if let Ok(mut con) = self.pool.get().await {
+            let _ = redis::cmd("EVAL")
+                .arg(RELEASE)
+                .arg(1)
+                .arg(owner_key(channel))
+                .arg(&self.id)
+                .query_async::<_, ()>(&mut *con)
+                .await;
+        }
+    }
+}
+
+fn owner_key(channel: &str) -> String {
+    format!("{}.$owner", channel)
+}
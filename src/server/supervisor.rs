@@ -0,0 +1,180 @@
+//! Supervision of stream producers.
+use super::{Sender, Sink};
+use crate::introspect::{Health, StreamStatus};
+use serde::Serialize;
+use std::any::Any;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
+
+/// first delay before restarting a producer
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+/// the delay is doubled on each restart up to this limit. It's reset if the
+/// producer ran for longer than that.
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Restart policy of a stream producer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Restart {
+    /// the stream is stopped once the producer exits
+    #[default]
+    Never,
+    /// restart the producer only if it panics
+    OnPanic,
+    /// restart the producer whenever it exits
+    Always,
+}
+
+impl Restart {
+    fn should_restart(&self, panicked: bool) -> bool {
+        match self {
+            Restart::Never => false,
+            Restart::OnPanic => panicked,
+            Restart::Always => true,
+        }
+    }
+}
+
+/// aborts the task when dropped, so the producer is stopped
+/// when the supervisor itself is aborted
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        return msg.to_string();
+    }
+
+    if let Some(msg) = payload.downcast_ref::<String>() {
+        return msg.clone();
+    }
+
+    "unknown panic".into()
+}
+
+fn update<F: FnOnce(&mut StreamStatus)>(status: &Mutex<StreamStatus>, f: F) {
+    if let Ok(mut status) = status.lock() {
+        f(&mut status)
+    }
+}
+
+/// run the producer and restart it according to the restart policy.
+pub(crate) fn supervise<T, F, Fut>(
+    name: String,
+    restart: Restart,
+    status: Arc<Mutex<StreamStatus>>,
+    sender: Sender<T>,
+    producer: F,
+) -> JoinHandle<()>
+where
+    T: Serialize + Send + Sync + 'static,
+    F: Fn(Sender<T>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut backoff = RESTART_BACKOFF;
+        loop {
+            let started = Instant::now();
+            let mut task = AbortOnDrop(tokio::spawn(producer(sender.clone())));
+            let (panicked, error) = match (&mut task.0).await {
+                Ok(_) => (false, "producer returned".to_string()),
+                Err(err) if err.is_panic() => (true, panic_message(err.into_panic())),
+                Err(err) => (false, err.to_string()),
+            };
+
+            log::error!("stream '{}' producer exited: {}", name, error);
+            if !restart.should_restart(panicked) {
+                update(&status, |status| {
+                    status.health = if panicked {
+                        Health::Failed
+                    } else {
+                        Health::Stopped
+                    };
+                    status.error = Some(error);
+                });
+                return;
+            }
+
+            if started.elapsed() > MAX_RESTART_BACKOFF {
+                backoff = RESTART_BACKOFF;
+            }
+
+            update(&status, |status| {
+                status.health = Health::Restarting;
+                status.error = Some(error);
+            });
+
+            log::debug!("restarting stream '{}' in {:?}", name, backoff);
+            sleep(backoff).await;
+            backoff = std::cmp::min(backoff * 2, MAX_RESTART_BACKOFF);
+
+            update(&status, |status| {
+                status.health = Health::Running;
+                status.restarts += 1;
+            });
+        }
+    })
+}
+
+impl Sink {
+    /// run the producer of this sink under supervision. The producer is called
+    /// with a clone of sender each time it's (re)started according to the restart
+    /// policy. The producer is stopped when the sink is dropped.
+    pub fn supervise<S, T, F, Fut>(
+        &mut self,
+        name: S,
+        restart: Restart,
+        sender: Sender<T>,
+        producer: F,
+    ) where
+        S: Into<String>,
+        T: Serialize + Send + Sync + 'static,
+        F: Fn(Sender<T>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let task = supervise(name.into(), restart, self.status(), sender, producer);
+        self.attach(task);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn failed() {
+        let (sender, mut sink) = Sender::<u32>::new();
+        sink.supervise("test", Restart::Never, sender, |_| async {
+            panic!("producer failed");
+        });
+
+        // all senders are dropped once the supervisor gives up
+        assert!(sink.recv().await.is_none());
+
+        let status = sink.status().lock().unwrap().clone();
+        assert_eq!(status.health, Health::Failed);
+        assert_eq!(status.error.as_deref(), Some("producer failed"));
+    }
+
+    #[tokio::test]
+    async fn restart() {
+        let (sender, mut sink) = Sender::<u32>::new();
+        sink.supervise("test", Restart::Always, sender, |sender| async move {
+            let _ = sender.send(&1).await;
+        });
+
+        assert!(sink.recv().await.is_some());
+        // the producer is restarted after it returns
+        assert!(sink.recv().await.is_some());
+
+        let status = sink.status().lock().unwrap().clone();
+        assert!(status.restarts >= 1);
+        assert_eq!(status.error.as_deref(), Some("producer returned"));
+    }
+}
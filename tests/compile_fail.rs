@@ -0,0 +1,7 @@
+// checks the `object` macro rejects invalid traits with a
+// meaningful error pointing at the offending item.
+#[test]
+fn compile_fail() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
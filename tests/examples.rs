@@ -1,13 +1,14 @@
 extern crate rbus;
 use std::collections::HashMap;
-use std::time::Duration;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
 use protocol::ObjectID;
 use rbus::client::Receiver;
-use rbus::server::{Object, Sender, Sink};
+use rbus::server::{Concurrency, Items, Object, Sender, Sink, Stream};
 use rbus::{object, protocol};
 // You can build your own complex object to pass around as
 // inputs and outputs as long as they are serder serializable
@@ -44,11 +45,217 @@ pub trait Calculator {
     // methods can be declared async.
     async fn get_data(&self) -> Result<Data>;
 
-    #[stream]
+    // only the latest date matters if publishing is slow
+    #[stream(buffer = 1, overflow = "coalesce-latest")]
     async fn date(&self, rec: Sender<u32>);
 
-    #[stream]
+    // the producer is restarted if it exits
+    #[stream(restart = "always")]
     async fn names(&self, rec: Sender<String>);
+
+    // streams can take subscription parameters, each set of
+    // parameters is a separate stream.
+    #[stream]
+    async fn counter(&self, start: u32, rec: Sender<u32>);
+}
+
+// methods can take borrowed arguments, they are decoded
+// without copying when possible.
+#[object(module = "test", name = "echo", version = "1.0")]
+pub trait Echo {
+    /// returns the same message. docs and attributes like `cfg`
+    /// and `deprecated` are kept on the trait and the stub.
+    fn echo(&self, msg: &str) -> Result<String>;
+    fn size(&self, data: &[u8]) -> Result<usize>;
+
+    #[deprecated(note = "use size instead")]
+    fn length(&self, data: &[u8]) -> Result<usize>;
+
+    #[cfg(any())]
+    fn disabled(&self) -> Result<()>;
+}
+
+struct EchoImpl;
+
+impl Echo for EchoImpl {
+    fn echo(&self, msg: &str) -> Result<String> {
+        Ok(msg.into())
+    }
+
+    fn size(&self, data: &[u8]) -> Result<usize> {
+        Ok(data.len())
+    }
+
+    fn length(&self, data: &[u8]) -> Result<usize> {
+        Ok(data.len())
+    }
+}
+
+// object traits can be generic and have associated types. Use
+// #[returns(T)] when the method returns an alias of Result.
+type Return<T> = std::result::Result<T, String>;
+
+#[object(module = "test", name = "store", version = "1.0")]
+pub trait Store<K>
+where
+    K: Serialize + serde::de::DeserializeOwned + Send + Sync + 'static,
+{
+    type Value: Serialize + serde::de::DeserializeOwned + Send + Sync + 'static;
+
+    fn get(&self, key: K) -> Result<Option<Self::Value>>;
+
+    #[returns(usize)]
+    fn count(&self) -> Return<usize>;
+}
+
+struct StoreImpl(HashMap<String, u64>);
+
+impl Store<String> for StoreImpl {
+    type Value = u64;
+
+    fn get(&self, key: String) -> Result<Option<u64>> {
+        Ok(self.0.get(&key).cloned())
+    }
+
+    fn count(&self) -> Return<usize> {
+        Ok(self.0.len())
+    }
+}
+
+// calls can take a Stream of items sent by the client, and
+// a Sender to send items back to the client while the call runs.
+#[object(module = "test", name = "accumulator", version = "1.0")]
+#[async_trait::async_trait]
+pub trait Accumulator {
+    // the stub takes any stream of values
+    async fn sum(&self, values: Stream<u64>) -> Result<u64>;
+
+    // the stub returns a Session to send values and receive the running total
+    async fn running(&self, start: u64, values: Stream<u64>, totals: Sender<u64>) -> Result<u64>;
+
+    // results are streamed to the caller, the stub returns a Receiver
+    fn range(&self, count: u64) -> Result<Items<u64>>;
+}
+
+struct AccumulatorImpl;
+
+#[async_trait::async_trait]
+impl Accumulator for AccumulatorImpl {
+    async fn sum(&self, mut values: Stream<u64>) -> Result<u64> {
+        let mut total = 0;
+        while let Some(value) = values.recv().await {
+            total += value?;
+        }
+
+        Ok(total)
+    }
+
+    async fn running(
+        &self,
+        start: u64,
+        mut values: Stream<u64>,
+        totals: Sender<u64>,
+    ) -> Result<u64> {
+        let mut total = start;
+        while let Some(value) = values.recv().await {
+            total += value?;
+            totals.send(&total).await?;
+        }
+
+        Ok(total)
+    }
+
+    fn range(&self, count: u64) -> Result<Items<u64>> {
+        if count > 1000 {
+            anyhow::bail!("too many items");
+        }
+
+        Ok(Items::new(futures_util::stream::iter(0..count)))
+    }
+}
+
+// at most 4 calls to the device run at the same time,
+// and writes are serialized.
+#[object(module = "test", name = "device", concurrency = 4)]
+#[async_trait::async_trait]
+pub trait Device {
+    async fn read(&self) -> Result<u64>;
+
+    #[concurrency(1)]
+    async fn write(&self, value: u64) -> Result<()>;
+
+    // reset doesn't wait behind pending reads and writes
+    #[priority("high")]
+    fn reset(&self) -> Result<()>;
+
+    // the client can retry an increment, it's applied once
+    #[idempotent]
+    fn increment(&self, value: u64) -> Result<u64>;
+}
+
+// a whole object can also run only on the leader with #[object(leader)]
+#[object(module = "test", name = "scheduler", version = "1.0")]
+#[async_trait::async_trait]
+pub trait Scheduler {
+    fn next(&self) -> Result<u64>;
+
+    // the jobs are only scheduled by the leader replica of the module
+    #[stream(leader)]
+    async fn jobs(&self, rec: Sender<u64>);
+
+    #[stream]
+    async fn load(&self, rec: Sender<u64>);
+}
+
+#[derive(Clone)]
+struct SchedulerImpl;
+
+#[async_trait::async_trait]
+impl Scheduler for SchedulerImpl {
+    fn next(&self) -> Result<u64> {
+        Ok(1)
+    }
+
+    async fn jobs(&self, rec: Sender<u64>) {
+        let mut job = 0;
+        loop {
+            let _ = rec.send(&job).await;
+            job += 1;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    async fn load(&self, rec: Sender<u64>) {
+        loop {
+            let _ = rec.send(&0).await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+#[derive(Default)]
+struct DeviceImpl(AtomicU64);
+
+#[async_trait::async_trait]
+impl Device for DeviceImpl {
+    async fn read(&self) -> Result<u64> {
+        Ok(self.0.load(Ordering::SeqCst))
+    }
+
+    async fn write(&self, value: u64) -> Result<()> {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        self.0.store(value, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn reset(&self) -> Result<()> {
+        self.0.store(0, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn increment(&self, value: u64) -> Result<u64> {
+        Ok(self.0.fetch_add(value, Ordering::SeqCst) + value)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -87,6 +294,8 @@ impl Calculator for CalculatorImpl {
         loop {
             // sleep
             tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+            // idle while nobody is listening
+            rec.wait_for_subscribers().await;
             let _ = rec.send(&10).await;
         }
     }
@@ -99,6 +308,15 @@ impl Calculator for CalculatorImpl {
             let _ = rec.send(&name).await;
         }
     }
+
+    async fn counter(&self, start: u32, rec: Sender<u32>) {
+        let mut value = start;
+        loop {
+            let _ = rec.send(&value).await;
+            value += 1;
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+    }
 }
 
 struct StreamTest;
@@ -157,6 +375,90 @@ async fn test_encode() {
     assert_eq!(msg.data, message.data);
 }
 
+#[tokio::test]
+async fn borrowed() {
+    let echo = EchoObject::from(EchoImpl);
+
+    let request = protocol::Request::new(echo.id(), "echo")
+        .arg("hello")
+        .unwrap();
+    let out: protocol::Result<String> = echo.dispatch(request).await.unwrap().into();
+    assert_eq!(out.unwrap(), "hello");
+
+    let data = vec![0u8; 1024];
+    let request = protocol::Request::new(echo.id(), "size")
+        .arg(protocol::BytesRef::from(&data[..]))
+        .unwrap();
+    let out: protocol::Result<usize> = echo.dispatch(request).await.unwrap().into();
+    assert_eq!(out.unwrap(), 1024);
+
+    let request = protocol::Request::new(echo.id(), "disabled");
+    assert!(matches!(
+        echo.dispatch(request).await,
+        Err(protocol::Error::UnknownMethod(_))
+    ));
+}
+
+#[tokio::test]
+async fn parameterized_stream() {
+    let calc = CalculatorObject::from(CalculatorImpl);
+
+    let request = protocol::Request::new(calc.id(), "counter")
+        .arg(5u32)
+        .unwrap();
+    let mut sink = calc.stream("counter", &request.inputs).unwrap();
+    for expected in 5u32..8 {
+        let msg = sink.recv().await.unwrap();
+        let value: u32 = rmp_serde::decode::from_read_ref(&msg).unwrap();
+        assert_eq!(value, expected);
+    }
+
+    assert!(matches!(
+        calc.stream("names", &request.inputs),
+        Err(protocol::Error::UnknownStream(_))
+    ));
+}
+
+#[tokio::test]
+async fn generic() {
+    let mut values = HashMap::new();
+    values.insert("a".to_string(), 10u64);
+    let store: StoreObject<_, String> = StoreObject::from(StoreImpl(values));
+
+    let request = protocol::Request::new(store.id(), "get").arg("a").unwrap();
+    let out: protocol::Result<Option<u64>> = store.dispatch(request).await.unwrap().into();
+    assert_eq!(out.unwrap(), Some(10));
+
+    let request = protocol::Request::new(store.id(), "count");
+    let out: protocol::Result<usize> = store.dispatch(request).await.unwrap().into();
+    assert_eq!(out.unwrap(), 1);
+
+    // the stub takes associated types as type parameters
+    let _: fn(rbus::Client) -> StoreStub<String, u64> = StoreStub::from;
+}
+
+#[tokio::test]
+async fn concurrency() {
+    let device = DeviceObject::from(DeviceImpl::default());
+    assert_eq!(device.concurrency(), Concurrency::new(4).method("write", 1));
+
+    let calc = CalculatorObject::from(CalculatorImpl);
+    assert_eq!(calc.concurrency(), Concurrency::unlimited());
+}
+
+#[tokio::test]
+async fn leader_streams() {
+    let scheduler = SchedulerObject::from(SchedulerImpl);
+    assert!(!scheduler.leader());
+
+    let streams = scheduler.streams().unwrap();
+    assert!(streams.contains_key("load"));
+    assert!(!streams.contains_key("jobs"));
+
+    let streams = scheduler.leader_streams().unwrap();
+    assert_eq!(streams.keys().collect::<Vec<_>>(), ["jobs"]);
+}
+
 #[ignore]
 #[tokio::test]
 async fn full() {
@@ -170,7 +472,7 @@ async fn full() {
     // create the module (server)
     let mut server = rbus::Server::new(pool.clone(), MODULE, 3).unwrap();
     // register the object
-    server.register(calc);
+    server.register(calc).unwrap();
 
     println!("running server");
     tokio::spawn(server.run());
@@ -213,6 +515,448 @@ async fn full() {
             break;
         }
     }
+
+    // parameterized streams are started on subscription
+    let mut receiver = calc.counter(10).await.unwrap();
+    let msg = receiver.recv().await.unwrap().unwrap();
+    assert!(msg >= 10);
+}
+
+#[ignore]
+#[tokio::test]
+async fn versions() {
+    let pool = rbus::pool("redis://localhost:6379").await.unwrap();
+
+    const MODULE: &str = "versions";
+    let mut server = rbus::Server::new(pool, MODULE, 1).unwrap();
+    let id = ObjectID::new("calculator", "1.2");
+    server
+        .register(rbus::server::SimpleObject::new(id.clone()))
+        .unwrap();
+    // calls to calculator@1.0 are served by calculator@1.2
+    server.alias(&id, "1.0").unwrap();
+    assert!(server.alias(&id, "1.0").is_err());
+    assert!(server.alias(&id, "2.0").is_err());
+    tokio::spawn(server.run());
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let client = rbus::Client::new("redis://localhost:6379").await.unwrap();
+    let info = client.introspect(MODULE).await.unwrap();
+    assert_eq!(info.versions("calculator"), vec!["1.0", "1.2"]);
+
+    let id = client.resolve(MODULE, "calculator", "^1").await.unwrap();
+    assert_eq!(id.version, "1.2");
+    let id = client.resolve(MODULE, "calculator", "~1.0").await.unwrap();
+    assert_eq!(id.version, "1.0");
+    assert!(client.resolve(MODULE, "calculator", "^2").await.is_err());
+}
+
+#[ignore]
+#[tokio::test]
+async fn instances() {
+    let pool = rbus::pool("redis://localhost:6379").await.unwrap();
+
+    const MODULE: &str = "instances";
+    let mut server = rbus::Server::new(pool, MODULE, 3).unwrap();
+    server
+        .register_instance(CalculatorObject::from(CalculatorImpl), "a")
+        .unwrap();
+    server
+        .register_instance(CalculatorObject::from(CalculatorImpl), "b")
+        .unwrap();
+    assert!(server
+        .register_instance(CalculatorObject::from(CalculatorImpl), "a")
+        .is_err());
+    tokio::spawn(server.run());
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let client = rbus::Client::new("redis://localhost:6379").await.unwrap();
+    let info = client.introspect(MODULE).await.unwrap();
+    let id = ObjectID::new("calculator", "1.0").with_instance("b");
+    assert_eq!(id.to_string(), "calculator@1.0/b");
+    assert!(info.object(&id).is_some());
+
+    let calc = CalculatorStub::new(MODULE, client).with_instance("b");
+    assert_eq!(calc.add(1.0, 2.0).await.unwrap(), (3.0, -1.0));
+}
+
+#[ignore]
+#[tokio::test]
+async fn dynamic() {
+    let pool = rbus::pool("redis://localhost:6379").await.unwrap();
+
+    const MODULE: &str = "dynamic";
+    let server = rbus::Server::new(pool, MODULE, 3).unwrap();
+    let handle = server.run();
+
+    let client = rbus::Client::new("redis://localhost:6379").await.unwrap();
+    let calc = CalculatorStub::new(MODULE, client).with_instance("sda");
+
+    let id = ObjectID::new("calculator", "1.0").with_instance("sda");
+    handle
+        .register_instance(CalculatorObject::from(CalculatorImpl), "sda")
+        .unwrap();
+    let objects: Vec<String> = handle.objects().iter().map(|id| id.to_string()).collect();
+    assert_eq!(objects, vec!["calculator@1.0/sda"]);
+    assert_eq!(calc.add(1.0, 2.0).await.unwrap(), (3.0, -1.0));
+
+    handle.unregister(&id).unwrap();
+    assert!(handle.objects().is_empty());
+    assert!(handle.unregister(&id).is_err());
+}
+
+#[ignore]
+#[tokio::test]
+async fn serialized() {
+    let pool = rbus::pool("redis://localhost:6379").await.unwrap();
+
+    const MODULE: &str = "serialized";
+    let mut server = rbus::Server::new(pool, MODULE, 10).unwrap();
+    server
+        .register(DeviceObject::from(DeviceImpl::default()))
+        .unwrap();
+    tokio::spawn(server.run());
+
+    let client = rbus::Client::new("redis://localhost:6379").await.unwrap();
+    let device = DeviceStub::new(MODULE, client);
+
+    // writes run one at a time even if the module has enough workers
+    let started = Instant::now();
+    let writes = (1..=3).map(|value| device.write(value));
+    for result in futures_util::future::join_all(writes).await {
+        result.unwrap();
+    }
+    assert!(started.elapsed() >= Duration::from_millis(600));
+    assert!(device.read().await.unwrap() > 0);
+
+    device.reset().await.unwrap();
+    assert_eq!(device.read().await.unwrap(), 0);
+}
+
+#[ignore]
+#[tokio::test]
+async fn retries() {
+    use rbus::client::{Failure, Retry};
+
+    let pool = rbus::pool("redis://localhost:6379").await.unwrap();
+
+    const MODULE: &str = "retries";
+    let mut server = rbus::Server::new(pool, MODULE, 2).unwrap();
+    server
+        .register(DeviceObject::from(DeviceImpl::default()))
+        .unwrap();
+    tokio::spawn(server.run());
+
+    let client = rbus::Client::new("redis://localhost:6379")
+        .await
+        .unwrap()
+        .with_timeout(Duration::from_secs(1))
+        .with_retry(Retry::new(3).on(&[Failure::Timeout, Failure::Connection]));
+
+    let device = DeviceStub::new(MODULE, client.clone());
+    assert_eq!(device.increment(2).await.unwrap(), 2);
+
+    // a retry of a request that already ran gets the same response
+    let request = protocol::Request::new(ObjectID::new("device", "1.0"), "increment")
+        .arg(5u64)
+        .unwrap()
+        .idempotent();
+    for _ in 0..2 {
+        let output = client.request(MODULE, request.clone()).await.unwrap();
+        let value: protocol::Result<u64> = output.into();
+        assert_eq!(value.unwrap(), 7);
+    }
+    assert_eq!(device.read().await.unwrap(), 7);
+
+    // calls time out if nobody serves the module
+    let device = DeviceStub::new("retries-nobody", client);
+    assert!(matches!(
+        device.read().await,
+        Err(protocol::Error::Timeout(_))
+    ));
+}
+
+#[ignore]
+#[tokio::test]
+async fn breaker() {
+    use rbus::client::Breaker;
+
+    const MODULE: &str = "breaker";
+    let client = rbus::Client::new("redis://localhost:6379")
+        .await
+        .unwrap()
+        .with_timeout(Duration::from_secs(1))
+        .with_breaker(Breaker::new(2, Duration::from_secs(2)).discovery(true));
+    let device = DeviceStub::new(MODULE, client);
+
+    // nobody serves the module, calls fail without waiting
+    for _ in 0..2 {
+        let started = Instant::now();
+        assert!(matches!(
+            device.read().await,
+            Err(protocol::Error::CircuitOpen(_))
+        ));
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    let pool = rbus::pool("redis://localhost:6379").await.unwrap();
+    let mut server = rbus::Server::new(pool, MODULE, 1).unwrap();
+    server
+        .register(DeviceObject::from(DeviceImpl::default()))
+        .unwrap();
+    tokio::spawn(server.run());
+
+    // the probe after the cooldown closes the circuit
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(device.read().await.unwrap(), 0);
+    assert_eq!(device.read().await.unwrap(), 0);
+}
+
+#[ignore]
+#[tokio::test]
+async fn breaker_without_info() {
+    use bb8_redis::redis::AsyncCommands;
+    use rbus::client::Breaker;
+
+    const MODULE: &str = "breaker-zbus";
+    let queue = protocol::Request::new(ObjectID::new("device", "1.0"), "read").queue(MODULE);
+
+    // a server of another zbus implementation, it never publishes the module info
+    let pool = rbus::pool("redis://localhost:6379").await.unwrap();
+    tokio::spawn(async move {
+        let mut con = pool.get().await.unwrap();
+        loop {
+            let request: Option<(String, protocol::Request)> = con.blpop(&queue, 1).await.unwrap();
+            if let Some((_, request)) = request {
+                let output = protocol::Output::from(Ok::<_, String>(42u64));
+                let response = protocol::Response::new(&request.id, request.codec(), Ok(output));
+                con.rpush::<_, _, ()>(&request.reply_to, &response)
+                    .await
+                    .unwrap();
+            }
+        }
+    });
+
+    let client = rbus::Client::new("redis://localhost:6379")
+        .await
+        .unwrap()
+        .with_timeout(Duration::from_secs(1))
+        .with_breaker(Breaker::default());
+    let device = DeviceStub::new(MODULE, client);
+
+    // the module is not checked by default, the calls are served
+    for _ in 0..3 {
+        assert_eq!(device.read().await.unwrap(), 42);
+    }
+}
+
+#[ignore]
+#[tokio::test]
+async fn replicas() {
+    use bb8_redis::redis::AsyncCommands;
+
+    let pool = rbus::pool("redis://localhost:6379").await.unwrap();
+
+    const MODULE: &str = "replicas";
+    // a request left by replica 'c' that is gone
+    let request = protocol::Request::new(ObjectID::new("calculator", "1.0"), "add")
+        .arg(1f64)
+        .unwrap()
+        .arg(2f64)
+        .unwrap();
+    let mut con = pool.get().await.unwrap();
+    con.sadd::<_, _, ()>("replicas.$replicas", "c")
+        .await
+        .unwrap();
+    con.rpush::<_, _, ()>("replicas.$pending.c", &request)
+        .await
+        .unwrap();
+    drop(con);
+
+    for replica in ["a", "b"] {
+        let mut server = rbus::Server::new(pool.clone(), MODULE, 1)
+            .unwrap()
+            .with_replica(replica);
+        server
+            .register(CalculatorObject::from(CalculatorImpl))
+            .unwrap();
+        tokio::spawn(server.run());
+    }
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let client = rbus::Client::new("redis://localhost:6379").await.unwrap();
+    let mut replicas: Vec<String> = client
+        .replicas(MODULE)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter_map(|info| info.replica)
+        .collect();
+    replicas.sort();
+    assert_eq!(replicas, vec!["a", "b"]);
+
+    // the request of the gone replica is served by one of the others
+    let mut con = pool.get().await.unwrap();
+    let response: Option<(String, protocol::Response)> = con.blpop(&request.id, 5).await.unwrap();
+    assert!(response.is_some());
+
+    let calc = CalculatorStub::new(MODULE, client);
+    for _ in 0..4 {
+        assert_eq!(calc.add(1.0, 2.0).await.unwrap(), (3.0, -1.0));
+    }
+}
+
+#[ignore]
+#[tokio::test]
+async fn leader() {
+    let pool = rbus::pool("redis://localhost:6379").await.unwrap();
+
+    const MODULE: &str = "leader";
+    let mut handles = vec![];
+    for replica in ["a", "b"] {
+        let mut server = rbus::Server::new(pool.clone(), MODULE, 1)
+            .unwrap()
+            .with_replica(replica);
+        server
+            .register(SchedulerObject::from(SchedulerImpl))
+            .unwrap();
+        server
+            .register(DeviceObject::from(DeviceImpl::default()))
+            .unwrap();
+        server.leader(&ObjectID::new("device", "1.0")).unwrap();
+        handles.push(server.run());
+    }
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    // exactly one replica is the leader
+    let client = rbus::Client::new("redis://localhost:6379").await.unwrap();
+    let leader = client.leader(MODULE).await.unwrap().unwrap();
+    let leading: Vec<bool> = handles.iter().map(|handle| handle.is_leader()).collect();
+    assert_eq!(leading, [leader == "a", leader == "b"]);
+
+    // the calls to a leader object are served by the leader
+    let device = DeviceStub::new(MODULE, client.clone());
+    device.write(10).await.unwrap();
+    assert_eq!(device.read().await.unwrap(), 10);
+
+    // the leader streams are only published once
+    let stub = SchedulerStub::new(MODULE, client);
+    let mut jobs = stub.jobs().await.unwrap();
+    let first = jobs.recv().await.unwrap().unwrap();
+    let second = jobs.recv().await.unwrap().unwrap();
+    assert_eq!(second, first + 1);
+}
+
+#[ignore]
+#[tokio::test]
+async fn replay() {
+    use rbus::recorder::{Recorder, Replayer};
+
+    // a new version of echo that changed the response
+    struct ShoutImpl;
+
+    impl Echo for ShoutImpl {
+        fn echo(&self, msg: &str) -> Result<String> {
+            Ok(msg.to_uppercase())
+        }
+
+        fn size(&self, data: &[u8]) -> Result<usize> {
+            Ok(data.len())
+        }
+
+        fn length(&self, data: &[u8]) -> Result<usize> {
+            Ok(data.len())
+        }
+    }
+
+    let pool = rbus::pool("redis://localhost:6379").await.unwrap();
+    let path = std::env::temp_dir().join(format!("rbus-replay-{}.rec", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let recorder = Recorder::new(&path).await.unwrap();
+    let mut server = rbus::Server::new(pool.clone(), "replay-old", 1).unwrap();
+    server
+        .register(recorder.wrap(EchoObject::from(EchoImpl)))
+        .unwrap();
+    tokio::spawn(server.run());
+
+    let mut server = rbus::Server::new(pool, "replay-new", 1).unwrap();
+    server.register(EchoObject::from(ShoutImpl)).unwrap();
+    tokio::spawn(server.run());
+
+    let client = rbus::Client::new("redis://localhost:6379").await.unwrap();
+    let id = ObjectID::new("echo", "1.0");
+    let request = protocol::Request::new(id.clone(), "echo")
+        .arg("hello")
+        .unwrap()
+        .with_priority(protocol::Priority::High);
+    client.request("replay-old", request).await.unwrap();
+    let request = protocol::Request::new(id, "size")
+        .arg(protocol::BytesRef::from(&[0u8; 4][..]))
+        .unwrap();
+    client.request("replay-old", request).await.unwrap();
+
+    let replayer = Replayer::new(client.clone(), "replay-old");
+    assert!(replayer.replay(&path).await.unwrap().ok());
+
+    // only the changed call is reported, with the recorded request
+    let replayer = Replayer::new(client, "replay-new");
+    let report = replayer.replay(&path).await.unwrap();
+    let _ = std::fs::remove_file(&path);
+
+    assert_eq!(report.total, 2);
+    assert_eq!(report.mismatches.len(), 1);
+    let mismatch = &report.mismatches[0];
+    assert_eq!(mismatch.record.request.method, "echo");
+    assert_eq!(mismatch.record.request.priority, protocol::Priority::High);
+    let output: protocol::Result<String> = mismatch.response.output.clone().into();
+    assert_eq!(output.unwrap(), "HELLO");
+}
+
+#[ignore]
+#[tokio::test]
+async fn pipes() {
+    let pool = rbus::pool("redis://localhost:6379").await.unwrap();
+
+    let mut server = rbus::Server::new(pool, "test", 3).unwrap();
+    server
+        .register(AccumulatorObject::from(AccumulatorImpl))
+        .unwrap();
+    tokio::spawn(server.run());
+
+    let client = rbus::Client::new("redis://localhost:6379").await.unwrap();
+    let acc = AccumulatorStub::from(client);
+
+    // client-streaming
+    let values = futures_util::stream::iter(1..=100u64);
+    assert_eq!(acc.sum(values).await.unwrap(), 5050);
+
+    // bidirectional
+    let mut session = acc.running(10).await.unwrap();
+    for value in 1..=3u64 {
+        session.send(&value).await.unwrap();
+        let total = session.recv().await.unwrap().unwrap();
+        assert_eq!(total, 10 + value * (value + 1) / 2);
+    }
+
+    session.close().await;
+    assert!(session.recv().await.is_none());
+    assert_eq!(session.finish().await.unwrap(), 16);
+
+    // streamed results
+    use futures_util::StreamExt;
+    let items: Vec<u64> = acc
+        .range(100)
+        .await
+        .unwrap()
+        .map(|item| item.unwrap())
+        .collect()
+        .await;
+    assert_eq!(items, (0..100).collect::<Vec<_>>());
+
+    let mut items = acc.range(1001).await.unwrap();
+    assert!(items.recv().await.unwrap().is_err());
+    assert!(items.recv().await.is_none());
 }
 
 #[ignore]
@@ -229,7 +973,7 @@ async fn testing_streams() {
     // create the module (server)
     let mut server = rbus::Server::new(pool.clone(), MODULE, 3).unwrap();
     // register the object
-    server.register(calc);
+    server.register(calc).unwrap();
 
     tokio::spawn(server.run());
 
@@ -0,0 +1,6 @@
+#[rbus::object(name = "calculator", version = 1.0)]
+pub trait Calculator {
+    fn add(&self, a: f64, b: f64) -> anyhow::Result<f64>;
+}
+
+fn main() {}
@@ -0,0 +1,7 @@
+#[rbus::object(name = "device")]
+pub trait Device {
+    #[concurrency(0)]
+    fn write(&self, value: u64) -> anyhow::Result<()>;
+}
+
+fn main() {}
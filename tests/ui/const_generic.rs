@@ -0,0 +1,6 @@
+#[rbus::object(name = "store")]
+pub trait Store<const N: usize> {
+    fn get(&self, key: String) -> anyhow::Result<String>;
+}
+
+fn main() {}
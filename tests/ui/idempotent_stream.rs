@@ -0,0 +1,8 @@
+#[rbus::object(name = "clock")]
+pub trait Clock {
+    #[stream]
+    #[idempotent]
+    async fn ticks(&self, rec: rbus::server::Sender<u64>);
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[rbus::object(name = "files")]
+pub trait Files {
+    async fn copy(&self, chunks: rbus::server::Stream<u64>) -> anyhow::Result<rbus::server::Items<u64>>;
+}
+
+fn main() {}
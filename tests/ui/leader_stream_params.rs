@@ -0,0 +1,7 @@
+#[rbus::object(name = "logs")]
+pub trait Logs {
+    #[stream(leader)]
+    async fn follow(&self, service: String, rec: rbus::server::Sender<String>);
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[rbus::object(name = "store")]
+pub trait Store<'a> {
+    fn get(&self, key: &'a str) -> anyhow::Result<String>;
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[rbus::object(name = "files")]
+pub trait Files {
+    fn sum(&self, values: rbus::server::Stream<u64>) -> anyhow::Result<u64>;
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[rbus::object(name = "files")]
+pub trait Files {
+    async fn merge(&self, a: rbus::server::Stream<u64>, b: rbus::server::Stream<u64>) -> anyhow::Result<u64>;
+}
+
+fn main() {}
@@ -0,0 +1,7 @@
+#[rbus::object(name = "device")]
+pub trait Device {
+    #[priority("urgent")]
+    fn stop(&self) -> anyhow::Result<()>;
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[rbus::object(name = "calculator")]
+pub trait Calculator {
+    fn add(self, a: f64, b: f64) -> anyhow::Result<f64>;
+}
+
+fn main() {}
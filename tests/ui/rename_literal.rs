@@ -0,0 +1,7 @@
+#[rbus::object(name = "calculator")]
+pub trait Calculator {
+    #[rename(Divide)]
+    fn divide(&self, a: f64, b: f64) -> anyhow::Result<f64>;
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+#[rbus::object(name = "calculator")]
+pub trait Calculator {
+    fn reset(&self);
+}
+
+fn main() {}
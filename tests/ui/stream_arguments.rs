@@ -0,0 +1,7 @@
+#[rbus::object(name = "clock")]
+pub trait Clock {
+    #[stream]
+    async fn ticks(&self);
+}
+
+fn main() {}
@@ -0,0 +1,7 @@
+#[rbus::object(name = "logs")]
+pub trait Logs {
+    #[stream]
+    async fn lines(&self, service: &str, rec: rbus::server::Sender<String>);
+}
+
+fn main() {}
@@ -0,0 +1,7 @@
+#[rbus::object(name = "clock")]
+pub trait Clock {
+    #[stream(buffer = 0, overflow = "drop-oldest")]
+    async fn ticks(&self, rec: rbus::server::Sender<u64>);
+}
+
+fn main() {}
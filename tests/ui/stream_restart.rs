@@ -0,0 +1,7 @@
+#[rbus::object(name = "clock")]
+pub trait Clock {
+    #[stream(restart = "sometimes")]
+    async fn ticks(&self, rec: rbus::server::Sender<u64>);
+}
+
+fn main() {}
@@ -0,0 +1,7 @@
+#[rbus::object(name = "clock")]
+pub trait Clock {
+    #[stream]
+    async fn ticks(&self, rec: Vec<u64>);
+}
+
+fn main() {}
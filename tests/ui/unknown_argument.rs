@@ -0,0 +1,6 @@
+#[rbus::object(names = "calculator")]
+pub trait Calculator {
+    fn add(&self, a: f64, b: f64) -> anyhow::Result<f64>;
+}
+
+fn main() {}